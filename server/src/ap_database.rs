@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+
+use crate::geolocation::{distance_m, Geolocator, Location};
+use crate::payload::{MacAddress, WifiScan};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+// Assumed for APs listed without an accuracy, roughly how far WiFi carries
+const DEFAULT_AP_RANGE_M: f64 = 100.0;
+
+// Column names accepted for each field, compared lowercase. Covers WiGLE
+// exports (MAC, CurrentLatitude, ..., AccuracyMeters) and simpler dumps.
+const BSSID_COLUMNS: &[&str] = &["bssid", "mac", "macaddress", "netid"];
+const LAT_COLUMNS: &[&str] = &["lat", "latitude", "currentlatitude", "trilat"];
+const LNG_COLUMNS: &[&str] = &["lon", "lng", "longitude", "currentlongitude", "trilong"];
+const ACCURACY_COLUMNS: &[&str] = &["accuracy", "accuracymeters", "range"];
+
+/// Where an access point is known to be
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnownAp {
+    pub lat: f64,
+    pub lng: f64,
+    pub accuracy: Option<f64>,
+}
+
+/// Access point positions loaded from a CSV export, for locating scans
+/// without sending them anywhere
+#[derive(Debug, Default)]
+pub struct ApDatabase {
+    aps: HashMap<MacAddress, KnownAp>,
+}
+
+impl ApDatabase {
+    pub fn load(path: &str) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .map_err(|e| format!("Unable to read AP database {}: {}", path, e))?;
+        Self::parse(BufReader::new(file)).map_err(|e| format!("Invalid AP database {}: {}", path, e).into())
+    }
+
+    /// Read a CSV with a header row naming at least the BSSID, latitude and
+    /// longitude columns. Anything before the header (WiGLE puts a line of
+    /// app details first) and rows which don't parse are skipped.
+    pub fn parse(reader: impl BufRead) -> Result<Self> {
+        let mut columns: Option<(usize, usize, usize, Option<usize>)> = None;
+        let mut database = ApDatabase::default();
+        for line in reader.lines() {
+            let line = line?;
+            let fields = split_csv_line(&line);
+            let (bssid, lat, lng, accuracy) = match columns {
+                Some(columns) => columns,
+                None => {
+                    columns = find_columns(&fields);
+                    continue;
+                }
+            };
+
+            let field = |idx: usize| fields.get(idx).map(|field| field.trim());
+            let bssid = match field(bssid).and_then(MacAddress::parse) {
+                Some(bssid) => bssid,
+                None => continue,
+            };
+            let (lat, lng) = match (field(lat).map(str::parse::<f64>), field(lng).map(str::parse::<f64>)) {
+                (Some(Ok(lat)), Some(Ok(lng))) if lat.abs() <= 90.0 && lng.abs() <= 180.0 => (lat, lng),
+                _ => continue,
+            };
+            let accuracy = accuracy
+                .and_then(field)
+                .and_then(|accuracy| accuracy.parse::<f64>().ok())
+                .filter(|accuracy| *accuracy > 0.0);
+            let ap = KnownAp { lat, lng, accuracy };
+
+            // Exports often list an AP once per sighting, keep the best one
+            let best = database.aps.entry(bssid).or_insert_with(|| ap.clone());
+            if ap.accuracy.unwrap_or(f64::MAX) < best.accuracy.unwrap_or(f64::MAX) {
+                *best = ap;
+            }
+        }
+        if columns.is_none() {
+            return Err("No header naming the BSSID, latitude and longitude columns".into());
+        }
+        Ok(database)
+    }
+
+    pub fn len(&self) -> usize {
+        self.aps.len()
+    }
+
+    pub fn get(&self, bssid: &MacAddress) -> Option<&KnownAp> {
+        self.aps.get(bssid)
+    }
+}
+
+impl Geolocator for ApDatabase {
+    /// Centroid of the known APs weighted by signal strength. The accuracy
+    /// covers how far the APs are spread around it plus how far each reaches.
+    fn locate(&self, scan: &WifiScan) -> Result<Location> {
+        let known: Vec<(&KnownAp, f64)> = scan.access_points.iter()
+            .filter_map(|ap| self.get(&ap.mac_address).map(|known| (known, rssi_weight(ap.rssi))))
+            .collect();
+        if known.is_empty() {
+            return Err(format!("None of the {} access points are in the AP database", scan.access_points.len()).into());
+        }
+
+        let total: f64 = known.iter().map(|(_, weight)| weight).sum();
+        let lat = known.iter().map(|(ap, weight)| ap.lat * weight).sum::<f64>() / total;
+        let lng = known.iter().map(|(ap, weight)| ap.lng * weight).sum::<f64>() / total;
+        let spread = known.iter()
+            .map(|(ap, weight)| distance_m(lat, lng, ap.lat, ap.lng).powi(2) * weight)
+            .sum::<f64>() / total;
+        let range = known.iter()
+            .map(|(ap, weight)| ap.accuracy.unwrap_or(DEFAULT_AP_RANGE_M) * weight)
+            .sum::<f64>() / total;
+        Ok(Location { lat, lng, accuracy: spread.sqrt() + range, ellipse: None })
+    }
+}
+
+/// Weight by received amplitude, an AP 20dB stronger is usually much
+/// closer and counts ten times as much
+fn rssi_weight(rssi: i8) -> f64 {
+    10f64.powf(rssi as f64 / 20.0)
+}
+
+fn find_columns(header: &[String]) -> Option<(usize, usize, usize, Option<usize>)> {
+    let find = |names: &[&str]| header.iter().position(|column| names.contains(&column.trim().to_ascii_lowercase().as_str()));
+    Some((find(BSSID_COLUMNS)?, find(LAT_COLUMNS)?, find(LNG_COLUMNS)?, find(ACCURACY_COLUMNS)))
+}
+
+/// Split on commas outside double quotes, SSIDs may contain either
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::AccessPoint;
+
+    const WIGLE_CSV: &str = "WigleWifi-1.4,appRelease=2.26,model=Pixel,release=11,device=dev,display=x,board=y,brand=z
+MAC,SSID,AuthMode,FirstSeen,Channel,RSSI,CurrentLatitude,CurrentLongitude,AltitudeMeters,AccuracyMeters,Type
+00:11:32:68:43:a2,\"Cafe, \"\"Upstairs\"\"\",[ESS],2021-01-01 10:00:00,8,-70,37.0000,-122.0000,10,20,WIFI
+00:11:32:68:43:A2,Cafe,[ESS],2021-01-01 10:05:00,8,-70,37.5000,-122.5000,10,50,WIFI
+48:4a:e9:2b:7d:82,Other,[WPA2],2021-01-01 10:00:00,1,-80,37.0010,-122.0000,10,,WIFI
+not a mac,Broken,[ESS],2021-01-01 10:00:00,1,-80,37.0,-122.0,10,5,WIFI
+";
+
+    fn ap(mac: [u8; 6], rssi: i8) -> AccessPoint {
+        AccessPoint { mac_address: MacAddress(mac), channel: 1, rssi }
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let database = ApDatabase::parse(WIGLE_CSV.as_bytes())?;
+        assert_eq!(database.len(), 2);
+        assert_eq!(
+            database.get(&MacAddress([0x00, 0x11, 0x32, 0x68, 0x43, 0xa2])),
+            Some(&KnownAp { lat: 37.0, lng: -122.0, accuracy: Some(20.0) })
+        );
+        assert_eq!(database.get(&MacAddress([0x48, 0x4a, 0xe9, 0x2b, 0x7d, 0x82])).unwrap().accuracy, None);
+
+        let database = ApDatabase::parse("bssid,lat,lon\n484AE92B7D80,1.5,2.5\n".as_bytes())?;
+        assert_eq!(database.len(), 1);
+        assert!(ApDatabase::parse("ssid,lat,lon\nfoo,1,2\n".as_bytes()).is_err());
+        assert_eq!(split_csv_line("a,\"b,\"\"c\"\"\",d"), vec!["a", "b,\"c\"", "d"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_locate() -> Result<()> {
+        let database = ApDatabase::parse("bssid,lat,lon,accuracy\n\
+            00:00:00:00:00:01,10.0,20.0,10\n\
+            00:00:00:00:00:02,10.001,20.0,10\n".as_bytes())?;
+
+        let single = WifiScan { version: 0, access_points: vec![ap([0, 0, 0, 0, 0, 1], -60)], ssid: String::new() };
+        assert_eq!(database.locate(&single)?, Location { lat: 10.0, lng: 20.0, accuracy: 10.0, ellipse: None });
+
+        // Equally strong APs put us halfway, a 20dB stronger one pulls us
+        // ten times closer to it
+        let mut scan = WifiScan {
+            version: 0,
+            access_points: vec![ap([0, 0, 0, 0, 0, 1], -60), ap([0, 0, 0, 0, 0, 2], -60), ap([9; 6], -30)],
+            ssid: String::new(),
+        };
+        let location = database.locate(&scan)?;
+        assert!((location.lat - 10.0005).abs() < 1e-9);
+        assert!((location.accuracy - (55.6 + 10.0)).abs() < 0.5);
+        scan.access_points[1].rssi = -80;
+        assert!((database.locate(&scan)?.lat - (10.0 + 0.001 / 11.0)).abs() < 1e-9);
+
+        let unknown = WifiScan { version: 0, access_points: vec![ap([9; 6], -60)], ssid: String::new() };
+        assert!(database.locate(&unknown).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+
+use lru::LruCache;
+use serde::Serialize;
+
+use crate::geolocation::{distance_m, Location};
+use crate::payload::{MacAddress, WifiScan};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+const BUNDLED_HOTSPOT_OUIS: &str = include_str!("../hotspot_ouis.txt");
+// Furthest a device can be from an AP and still see it. Two fixes sharing a
+// fixed AP can't be further apart than twice this plus their accuracies.
+const MAX_AP_RANGE_M: f64 = 250.0;
+// APs whose last sighting is remembered, the least recently seen are
+// forgotten past this
+const MAX_SIGHTINGS: usize = 100_000;
+// APs known to move, likewise. Spoofed BSSIDs could otherwise grow it forever.
+const MAX_MOVING: usize = 10_000;
+
+/// Why an AP was left out of geolocation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum FilterReason {
+    // Randomised or otherwise made up BSSID, says nothing about where we are
+    LocallyAdministered,
+    // Made by a mobile hotspot vendor
+    HotspotOui { vendor: String },
+    // Seen by fixes too far apart for the AP to have stayed put
+    Moving,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FilteredAp {
+    pub mac_address: MacAddress,
+    #[serde(flatten)]
+    pub reason: FilterReason,
+}
+
+// Where an AP was last seen from
+#[derive(Debug, Clone)]
+struct Sighting {
+    lat: f64,
+    lng: f64,
+    accuracy: f64,
+}
+
+/// Drops APs which would mislead geolocation from scans, learning which APs
+/// move from the fixes made with the rest. What it learns is only kept in
+/// memory, a restart starts over.
+#[derive(Debug)]
+pub struct ApFilter {
+    hotspot_ouis: HashMap<[u8; 3], String>,
+    sightings: LruCache<MacAddress, Sighting>,
+    moving: LruCache<MacAddress, ()>,
+}
+
+impl ApFilter {
+    /// A filter using the bundled hotspot OUI table
+    pub fn new() -> Self {
+        ApFilter::with_capacity(MAX_SIGHTINGS, MAX_MOVING)
+    }
+
+    fn with_capacity(sightings: usize, moving: usize) -> Self {
+        let mut ap_filter = ApFilter {
+            hotspot_ouis: HashMap::new(),
+            sightings: LruCache::new(NonZeroUsize::new(sightings).unwrap()),
+            moving: LruCache::new(NonZeroUsize::new(moving).unwrap()),
+        };
+        ap_filter.add_hotspot_ouis(BUNDLED_HOTSPOT_OUIS).expect("Invalid bundled hotspot OUI table");
+        ap_filter
+    }
+
+    /// Add OUIs in the format of hotspot_ouis.txt
+    pub fn add_hotspot_ouis(&mut self, table: &str) -> Result<()> {
+        for (number, line) in table.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (oui, vendor) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let oui = parse_oui(oui).ok_or_else(|| format!("Invalid OUI on line {}: {}", number + 1, oui))?;
+            self.hotspot_ouis.insert(oui, vendor.trim().to_string());
+        }
+        Ok(())
+    }
+
+    pub fn load_hotspot_ouis(&mut self, path: &str) -> Result<()> {
+        let table = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read hotspot OUIs {}: {}", path, e))?;
+        self.add_hotspot_ouis(&table)
+    }
+
+    fn reason(&self, mac_address: &MacAddress) -> Option<FilterReason> {
+        if mac_address.is_locally_administered() {
+            return Some(FilterReason::LocallyAdministered);
+        }
+        if let Some(vendor) = self.hotspot_ouis.get(&mac_address.oui()) {
+            return Some(FilterReason::HotspotOui { vendor: vendor.clone() });
+        }
+        if self.moving.contains(mac_address) {
+            return Some(FilterReason::Moving);
+        }
+        None
+    }
+
+    /// Split a scan into the APs fit for geolocation and a report of the rest
+    pub fn filter(&self, scan: &WifiScan) -> (WifiScan, Vec<FilteredAp>) {
+        let mut kept = WifiScan { access_points: Vec::new(), ..scan.clone() };
+        let mut filtered = Vec::new();
+        for ap in &scan.access_points {
+            match self.reason(&ap.mac_address) {
+                Some(reason) => filtered.push(FilteredAp { mac_address: ap.mac_address, reason }),
+                None => kept.access_points.push(ap.clone()),
+            }
+        }
+        (kept, filtered)
+    }
+
+    /// Remember where the APs of a scan were seen from, returning those which
+    /// turn out to have moved since. They're filtered from then on.
+    pub fn record_fix(&mut self, scan: &WifiScan, location: &Location) -> Vec<MacAddress> {
+        let mut moved = Vec::new();
+        for ap in &scan.access_points {
+            let sighting = Sighting { lat: location.lat, lng: location.lng, accuracy: location.accuracy };
+            if let Some(previous) = self.sightings.put(ap.mac_address, sighting) {
+                let apart = distance_m(previous.lat, previous.lng, location.lat, location.lng);
+                if apart > 2.0 * MAX_AP_RANGE_M + previous.accuracy + location.accuracy && self.moving.put(ap.mac_address, ()).is_none() {
+                    moved.push(ap.mac_address);
+                }
+            }
+        }
+        moved
+    }
+}
+
+fn parse_oui(text: &str) -> Option<[u8; 3]> {
+    let mac = MacAddress::parse(&format!("{}000000", text.replace([':', '-'], "")))?;
+    Some(mac.oui())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scan_of_macs as scan;
+
+    fn location(lat: f64, lng: f64) -> Location {
+        Location { lat, lng, accuracy: 30.0, ellipse: None }
+    }
+
+    #[test]
+    fn test_filter() -> Result<()> {
+        let mut ap_filter = ApFilter::new();
+        ap_filter.add_hotspot_ouis("# test\n  48-4a-e9 Example Hotspots  # trailing\n\n")?;
+        assert!(ap_filter.add_hotspot_ouis("zz:zz:zz nope").is_err());
+
+        let (kept, filtered) = ap_filter.filter(&scan(&[
+            "00:11:32:68:43:A2", "02:11:32:68:43:A2", "66:EB:8C:00:09:DF", "00:15:FF:01:02:03", "48:4A:E9:2B:7D:82",
+        ]));
+        assert_eq!(kept, scan(&["00:11:32:68:43:A2"]));
+        let reasons: Vec<FilterReason> = filtered.iter().map(|filtered| filtered.reason.clone()).collect();
+        assert_eq!(reasons, vec![
+            FilterReason::LocallyAdministered,
+            FilterReason::LocallyAdministered,
+            FilterReason::HotspotOui { vendor: "Novatel Wireless".to_string() },
+            FilterReason::HotspotOui { vendor: "Example Hotspots".to_string() },
+        ]);
+        assert_eq!(
+            serde_json::to_value(&filtered[2])?,
+            serde_json::json!({"macAddress": "00:15:FF:01:02:03", "reason": "hotspot_oui", "vendor": "Novatel Wireless"})
+        );
+        // Listed vendors are filtered, the commented out ones aren't
+        assert_eq!(ap_filter.reason(&MacAddress::parse("00:1E:73:01:02:03").unwrap()), Some(FilterReason::HotspotOui { vendor: "ZTE".to_string() }));
+        assert_eq!(ap_filter.reason(&MacAddress::parse("50:C7:BF:01:02:03").unwrap()), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_moving() {
+        let mut ap_filter = ApFilter::new();
+        let home = scan(&["00:00:00:00:00:01", "00:00:00:00:00:02"]);
+        assert!(ap_filter.record_fix(&home, &location(51.5, -0.12)).is_empty());
+        // Nearby, within what the AP could reach
+        assert!(ap_filter.record_fix(&home, &location(51.503, -0.12)).is_empty());
+
+        // A few kilometres on, the one AP which came along has moved
+        let moved = ap_filter.record_fix(&scan(&["00:00:00:00:00:02"]), &location(51.55, -0.12));
+        assert_eq!(moved, vec![MacAddress::parse("00:00:00:00:00:02").unwrap()]);
+        let (kept, filtered) = ap_filter.filter(&home);
+        assert_eq!(kept, scan(&["00:00:00:00:00:01"]));
+        assert_eq!(filtered[0].reason, FilterReason::Moving);
+    }
+
+    #[test]
+    fn test_moving_capped() {
+        let mut ap_filter = ApFilter::with_capacity(10, 2);
+        let aps = scan(&["00:00:00:00:00:01", "00:00:00:00:00:02", "00:00:00:00:00:03"]);
+        ap_filter.record_fix(&aps, &location(51.5, -0.12));
+        assert_eq!(ap_filter.record_fix(&aps, &location(51.55, -0.12)).len(), 3);
+
+        // Only the two which moved last are still remembered
+        let (kept, _) = ap_filter.filter(&aps);
+        assert_eq!(kept, scan(&["00:00:00:00:00:01"]));
+    }
+}
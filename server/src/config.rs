@@ -0,0 +1,159 @@
+use std::net::Ipv4Addr;
+
+use serde::Deserialize;
+
+use crate::zone::{DomainName, ResponsePolicy, Zone};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// Zone settings as written in the config file, see zones_sample.json.
+/// Anything left out falls back to the command line options
+#[derive(Debug, Deserialize)]
+struct ZoneConfig {
+    domain: String,
+    output_dir: Option<String>,
+    allowed_devices: Option<Vec<String>>,
+    response_policy: Option<ResponsePolicy>,
+    nameservers: Option<Vec<String>>,
+    address: Option<Ipv4Addr>,
+    hostmaster: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    zones: Vec<ZoneConfig>,
+}
+
+/// Settings from the command line, applied to every zone which doesn't set its own
+#[derive(Debug, Clone)]
+pub struct ZoneDefaults {
+    pub nameservers: Option<Vec<DomainName>>,
+    pub address: Option<Ipv4Addr>,
+    pub hostmaster: Option<DomainName>,
+    pub output_dir: String,
+    pub response_policy: ResponsePolicy,
+}
+
+impl ZoneDefaults {
+    pub fn zone(&self, domain: &str) -> Zone {
+        let mut zone = Zone::new(domain);
+        if let Some(nameservers) = &self.nameservers {
+            zone.nameservers = nameservers.clone();
+        }
+        if let Some(hostmaster) = &self.hostmaster {
+            zone.hostmaster = hostmaster.clone();
+        }
+        zone.address = self.address;
+        zone.output_dir = self.output_dir.clone();
+        zone.response_policy = self.response_policy;
+        zone
+    }
+}
+
+/// Parse the zones from a JSON config file
+pub fn load_zones(path: &str, defaults: &ZoneDefaults) -> Result<Vec<Zone>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read config {}: {}", path, e))?;
+    parse_zones(&contents, defaults)
+}
+
+fn parse_zones(contents: &str, defaults: &ZoneDefaults) -> Result<Vec<Zone>> {
+    let config: Config = serde_json::from_str(contents)?;
+    let mut zones: Vec<Zone> = Vec::new();
+    for zone_config in config.zones {
+        let mut zone = defaults.zone(&zone_config.domain);
+        if zone.apex.labels().is_empty() {
+            return Err("Zone domain can't be empty, it would match every name".into());
+        }
+        if zones.iter().any(|other| other.apex == zone.apex) {
+            return Err(format!("Zone {} is configured twice", zone.apex).into());
+        }
+
+        if let Some(nameservers) = zone_config.nameservers {
+            if nameservers.is_empty() {
+                return Err(format!("Zone {} needs at least one nameserver", zone.apex).into());
+            }
+            zone.nameservers = nameservers.iter().map(|ns| DomainName::new(ns)).collect();
+        }
+        if let Some(hostmaster) = zone_config.hostmaster {
+            zone.hostmaster = DomainName::new(&hostmaster);
+        }
+        if let Some(address) = zone_config.address {
+            zone.address = Some(address);
+        }
+        if let Some(output_dir) = zone_config.output_dir {
+            zone.output_dir = output_dir;
+        }
+        if let Some(response_policy) = zone_config.response_policy {
+            zone.response_policy = response_policy;
+        }
+        zone.allowed_devices = zone_config.allowed_devices.map(|devices| {
+            devices
+                .iter()
+                .map(|device| device.to_ascii_uppercase())
+                .collect()
+        });
+        zones.push(zone);
+    }
+    Ok(zones)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults() -> ZoneDefaults {
+        ZoneDefaults {
+            nameservers: None,
+            address: None,
+            hostmaster: None,
+            output_dir: String::new(),
+            response_policy: ResponsePolicy::Ack,
+        }
+    }
+
+    #[test]
+    fn test_parse_zones() -> Result<()> {
+        let defaults = ZoneDefaults {
+            output_dir: "logs".to_string(),
+            ..defaults()
+        };
+        let zones = parse_zones(
+            r#"{"zones": [
+                {"domain": "i.mdp.im", "allowed_devices": ["mfrggzdf"], "response_policy": "nodata",
+                 "nameservers": ["ns.mdp.im"], "address": "192.0.2.1"},
+                {"domain": "foo.co.", "output_dir": "logs/foo"}
+            ]}"#,
+            &defaults,
+        )?;
+        assert_eq!(zones.len(), 2);
+        assert_eq!(zones[0].apex, DomainName::new("i.mdp.im"));
+        assert_eq!(zones[0].output_dir, "logs");
+        assert!(zones[0].allows_device("MFRGGZDF"));
+        assert!(!zones[0].allows_device("AAAAAAAA"));
+        assert_eq!(zones[0].response_policy, ResponsePolicy::NoData);
+        assert_eq!(zones[0].nameservers, vec![DomainName::new("ns.mdp.im")]);
+        assert_eq!(zones[0].address, Some(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(zones[1].apex, DomainName::new("foo.co"));
+        assert_eq!(zones[1].output_dir, "logs/foo");
+        assert!(zones[1].allows_device("AAAAAAAA"));
+        assert_eq!(zones[1].response_policy, ResponsePolicy::Ack);
+        assert_eq!(zones[1].nameservers, vec![DomainName::new("ns1.foo.co")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_zone() {
+        let defaults = defaults();
+        let config = r#"{"zones": [{"domain": "foo.co"}, {"domain": "FOO.co"}]}"#;
+        assert!(parse_zones(config, &defaults).is_err());
+    }
+
+    #[test]
+    fn test_empty_domain() {
+        let defaults = defaults();
+        assert!(parse_zones(r#"{"zones": [{"domain": ""}]}"#, &defaults).is_err());
+        assert!(parse_zones(r#"{"zones": [{"domain": "."}]}"#, &defaults).is_err());
+    }
+}
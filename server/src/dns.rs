@@ -5,16 +5,51 @@ use std::net::{Ipv4Addr, Ipv6Addr};
 type Error = Box<dyn std::error::Error>;
 type Result<T> = std::result::Result<T, Error>;
 
+/// Largest UDP payload we will ever advertise or accept via EDNS0
+pub const MAX_UDP_PAYLOAD: usize = 4096;
+/// Classic DNS limit for UDP messages without EDNS0 (RFC 1035)
+pub const DEFAULT_UDP_PAYLOAD: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// Writing went past a buffer's limit, see `DnsPacket::truncate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferFull;
+
+impl std::fmt::Display for BufferFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Packet doesn't fit the buffer")
+    }
+}
+
+impl std::error::Error for BufferFull {}
+
 pub struct BytePacketBuffer {
-    pub buf: [u8; 512],
+    pub buf: Vec<u8>,
     pub pos: usize,
+    limit: usize,
 }
 
 impl BytePacketBuffer {
-    pub fn new() -> BytePacketBuffer {
+    /// An empty buffer which grows on write up to `limit` bytes
+    pub fn with_limit(limit: usize) -> BytePacketBuffer {
+        BytePacketBuffer {
+            buf: Vec::with_capacity(limit),
+            pos: 0,
+            limit,
+        }
+    }
+
+    /// A buffer holding a received packet, ready to be parsed
+    pub fn from_bytes(bytes: &[u8]) -> BytePacketBuffer {
         BytePacketBuffer {
-            buf: [0; 512],
+            buf: bytes.to_vec(),
             pos: 0,
+            limit: bytes.len(),
         }
     }
 
@@ -23,16 +58,10 @@ impl BytePacketBuffer {
         self.get_range(0, len)
     }
 
-    fn pos(&self) -> usize {
+    pub fn pos(&self) -> usize {
         self.pos
     }
 
-    fn step(&mut self, steps: usize) -> Result<()> {
-        self.pos += steps;
-
-        Ok(())
-    }
-
     fn seek(&mut self, pos: usize) -> Result<()> {
         self.pos = pos;
 
@@ -40,7 +69,7 @@ impl BytePacketBuffer {
     }
 
     fn read(&mut self) -> Result<u8> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err("End of buffer".into());
         }
         let res = self.buf[self.pos];
@@ -50,17 +79,17 @@ impl BytePacketBuffer {
     }
 
     fn get(&mut self, pos: usize) -> Result<u8> {
-        if pos >= 512 {
+        if pos >= self.buf.len() {
             return Err("End of buffer".into());
         }
         Ok(self.buf[pos])
     }
 
     fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        if start + len >= 512 {
+        if start + len > self.buf.len() {
             return Err("End of buffer".into());
         }
-        Ok(&self.buf[start..start + len as usize])
+        Ok(&self.buf[start..start + len])
     }
 
     fn read_u16(&mut self) -> Result<u16> {
@@ -78,6 +107,13 @@ impl BytePacketBuffer {
         Ok(res)
     }
 
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let res = self.get_range(self.pos, len)?.to_vec();
+        self.pos += len;
+
+        Ok(res)
+    }
+
     fn read_qname(&mut self, outstr: &mut String) -> Result<()> {
         let mut pos = self.pos();
         let mut jumped = false;
@@ -123,8 +159,11 @@ impl BytePacketBuffer {
 
             outstr.push_str(delim);
 
+            // Keep the case as sent, resolvers using 0x20 encoding (randomised
+            // case) expect the question echoed back exactly. Compare names
+            // case-insensitively instead.
             let str_buffer = self.get_range(pos, len as usize)?;
-            outstr.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+            outstr.push_str(&String::from_utf8_lossy(str_buffer));
 
             delim = ".";
 
@@ -139,10 +178,15 @@ impl BytePacketBuffer {
     }
 
     fn write(&mut self, val: u8) -> Result<()> {
-        if self.pos >= 512 {
-            return Err("End of buffer".into());
+        if self.pos >= self.limit {
+            return Err(BufferFull.into());
+        }
+        if self.pos < self.buf.len() {
+            self.buf[self.pos] = val;
+        } else {
+            self.buf.resize(self.pos, 0);
+            self.buf.push(val);
         }
-        self.buf[self.pos] = val;
         self.pos += 1;
         Ok(())
     }
@@ -169,8 +213,17 @@ impl BytePacketBuffer {
         Ok(())
     }
 
+    fn write_bytes(&mut self, val: &[u8]) -> Result<()> {
+        for b in val {
+            self.write(*b)?;
+        }
+
+        Ok(())
+    }
+
     fn write_qname(&mut self, qname: &str) -> Result<()> {
-        for label in qname.split('.') {
+        // The root name (as used by OPT records) is just the terminating label
+        for label in qname.split('.').filter(|label| !label.is_empty()) {
             let len = label.len();
             if len > 0x3F {
                 return Err("Single label exceeds 63 characters of length".into());
@@ -188,6 +241,9 @@ impl BytePacketBuffer {
     }
 
     fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+        if pos >= self.buf.len() {
+            return Err("End of buffer".into());
+        }
         self.buf[pos] = val;
 
         Ok(())
@@ -305,7 +361,7 @@ impl DnsHeader {
                 | ((self.truncated_message as u8) << 1)
                 | ((self.authoritative_answer as u8) << 2)
                 | (self.opcode << 3)
-                | ((self.response as u8) << 7) as u8,
+                | ((self.response as u8) << 7),
         )?;
 
         buffer.write_u8(
@@ -331,8 +387,14 @@ pub enum QueryType {
     A,     // 1
     NS,    // 2
     CNAME, // 5
+    SOA,   // 6
+    NULL,  // 10
+    PTR,   // 12
     MX,    // 15
+    TXT,   // 16
     AAAA,  // 28
+    SRV,   // 33
+    OPT,   // 41
 }
 
 impl QueryType {
@@ -342,8 +404,14 @@ impl QueryType {
             QueryType::A => 1,
             QueryType::NS => 2,
             QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::NULL => 10,
+            QueryType::PTR => 12,
             QueryType::MX => 15,
+            QueryType::TXT => 16,
             QueryType::AAAA => 28,
+            QueryType::SRV => 33,
+            QueryType::OPT => 41,
         }
     }
 
@@ -352,8 +420,14 @@ impl QueryType {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            10 => QueryType::NULL,
+            12 => QueryType::PTR,
             15 => QueryType::MX,
+            16 => QueryType::TXT,
             28 => QueryType::AAAA,
+            33 => QueryType::SRV,
+            41 => QueryType::OPT,
             _ => QueryType::UNKNOWN(num),
         }
     }
@@ -395,7 +469,7 @@ pub enum DnsRecord {
     UNKNOWN {
         domain: String,
         qtype: u16,
-        data_len: u16,
+        data: Vec<u8>,
         ttl: u32,
     }, // 0
     A {
@@ -413,17 +487,59 @@ pub enum DnsRecord {
         host: String,
         ttl: u32,
     }, // 5
+    SOA {
+        domain: String,
+        m_name: String,
+        r_name: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    }, // 6
+    NULL {
+        domain: String,
+        data: Vec<u8>,
+        ttl: u32,
+    }, // 10
+    PTR {
+        domain: String,
+        host: String,
+        ttl: u32,
+    }, // 12
     MX {
         domain: String,
         priority: u16,
         host: String,
         ttl: u32,
     }, // 15
+    // Each entry is one <character-string> of at most 255 bytes
+    TXT {
+        domain: String,
+        data: Vec<Vec<u8>>,
+        ttl: u32,
+    }, // 16
     AAAA {
         domain: String,
         addr: Ipv6Addr,
         ttl: u32,
     }, // 28
+    SRV {
+        domain: String,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        host: String,
+        ttl: u32,
+    }, // 33
+    // EDNS0 pseudo-record (RFC 6891). The class field carries the sender's UDP
+    // payload size and the ttl field the extended rcode, version and flags.
+    OPT {
+        packet_len: u16,
+        flags: u32,
+        data: Vec<u8>,
+    }, // 41
 }
 
 impl DnsRecord {
@@ -433,7 +549,7 @@ impl DnsRecord {
 
         let qtype_num = buffer.read_u16()?;
         let qtype = QueryType::from_num(qtype_num);
-        let _ = buffer.read_u16()?;
+        let class = buffer.read_u16()?;
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
 
@@ -499,13 +615,84 @@ impl DnsRecord {
                     ttl,
                 })
             }
+            QueryType::SOA => {
+                let mut m_name = String::new();
+                buffer.read_qname(&mut m_name)?;
+                let mut r_name = String::new();
+                buffer.read_qname(&mut r_name)?;
+
+                Ok(DnsRecord::SOA {
+                    domain,
+                    m_name,
+                    r_name,
+                    serial: buffer.read_u32()?,
+                    refresh: buffer.read_u32()?,
+                    retry: buffer.read_u32()?,
+                    expire: buffer.read_u32()?,
+                    minimum: buffer.read_u32()?,
+                    ttl,
+                })
+            }
+            QueryType::NULL => {
+                let data = buffer.read_bytes(data_len as usize)?;
+
+                Ok(DnsRecord::NULL { domain, data, ttl })
+            }
+            QueryType::PTR => {
+                let mut ptr = String::new();
+                buffer.read_qname(&mut ptr)?;
+
+                Ok(DnsRecord::PTR {
+                    domain,
+                    host: ptr,
+                    ttl,
+                })
+            }
+            QueryType::TXT => {
+                let end = buffer.pos() + data_len as usize;
+                let mut data = Vec::new();
+                while buffer.pos() < end {
+                    let len = buffer.read()?;
+                    data.push(buffer.read_bytes(len as usize)?);
+                }
+                if buffer.pos() != end {
+                    return Err("TXT string overruns record data".into());
+                }
+
+                Ok(DnsRecord::TXT { domain, data, ttl })
+            }
+            QueryType::SRV => {
+                let priority = buffer.read_u16()?;
+                let weight = buffer.read_u16()?;
+                let port = buffer.read_u16()?;
+                let mut srv = String::new();
+                buffer.read_qname(&mut srv)?;
+
+                Ok(DnsRecord::SRV {
+                    domain,
+                    priority,
+                    weight,
+                    port,
+                    host: srv,
+                    ttl,
+                })
+            }
+            QueryType::OPT => {
+                let data = buffer.read_bytes(data_len as usize)?;
+
+                Ok(DnsRecord::OPT {
+                    packet_len: class,
+                    flags: ttl,
+                    data,
+                })
+            }
             QueryType::UNKNOWN(_) => {
-                buffer.step(data_len as usize)?;
+                let data = buffer.read_bytes(data_len as usize)?;
 
                 Ok(DnsRecord::UNKNOWN {
                     domain,
                     qtype: qtype_num,
-                    data_len,
+                    data,
                     ttl,
                 })
             }
@@ -604,8 +791,138 @@ impl DnsRecord {
                     buffer.write_u16(*octet)?;
                 }
             }
-            DnsRecord::UNKNOWN { .. } => {
-                println!("Skipping record: {:?}", self);
+            DnsRecord::SOA {
+                ref domain,
+                ref m_name,
+                ref r_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(m_name)?;
+                buffer.write_qname(r_name)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::NULL {
+                ref domain,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NULL.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+                buffer.write_bytes(data)?;
+            }
+            DnsRecord::PTR {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::PTR.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::TXT {
+                ref domain,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TXT.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                for string in data {
+                    if string.len() > 0xFF {
+                        return Err("TXT string exceeds 255 characters of length".into());
+                    }
+                    buffer.write_u8(string.len() as u8)?;
+                    buffer.write_bytes(string)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::SRV {
+                ref domain,
+                priority,
+                weight,
+                port,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SRV.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(priority)?;
+                buffer.write_u16(weight)?;
+                buffer.write_u16(port)?;
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::OPT {
+                packet_len,
+                flags,
+                ref data,
+            } => {
+                buffer.write_qname("")?;
+                buffer.write_u16(QueryType::OPT.to_num())?;
+                buffer.write_u16(packet_len)?;
+                buffer.write_u32(flags)?;
+                buffer.write_u16(data.len() as u16)?;
+                buffer.write_bytes(data)?;
+            }
+            DnsRecord::UNKNOWN {
+                ref domain,
+                qtype,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+                buffer.write_bytes(data)?;
             }
         }
 
@@ -656,9 +973,69 @@ impl DnsPacket {
             result.resources.push(rec);
         }
 
+        // RFC 6891 6.1.1: more than one OPT record makes the message malformed
+        let opt_count = result
+            .resources
+            .iter()
+            .filter(|rec| matches!(rec, DnsRecord::OPT { .. }))
+            .count();
+        if opt_count > 1 {
+            return Err("Multiple OPT records in additional section".into());
+        }
+
         Ok(result)
     }
 
+    /// Strip everything but the question and OPT record and set the TC bit,
+    /// telling the requester to retry over TCP for the full answer
+    pub fn truncate(&mut self) {
+        self.header.truncated_message = true;
+        self.answers.clear();
+        self.authorities.clear();
+        self.resources
+            .retain(|rec| matches!(rec, DnsRecord::OPT { .. }));
+    }
+
+    fn opt(&self) -> Option<(u16, u32)> {
+        self.resources.iter().find_map(|rec| match *rec {
+            DnsRecord::OPT {
+                packet_len, flags, ..
+            } => Some((packet_len, flags)),
+            _ => None,
+        })
+    }
+
+    /// The UDP payload size advertised by the sender, if it speaks EDNS0
+    pub fn edns_payload_size(&self) -> Option<u16> {
+        self.opt().map(|(packet_len, _)| packet_len)
+    }
+
+    /// The EDNS version requested by the sender, if it speaks EDNS0
+    pub fn edns_version(&self) -> Option<u8> {
+        self.opt().map(|(_, flags)| ((flags >> 16) & 0xFF) as u8)
+    }
+
+    /// Largest response we may send back over UDP to the sender of this packet.
+    /// Requesters advertising less than 512 bytes are treated as 512 (RFC 6891 6.2.5)
+    pub fn max_response_size(&self) -> usize {
+        match self.edns_payload_size() {
+            Some(size) => (size as usize).clamp(DEFAULT_UDP_PAYLOAD, MAX_UDP_PAYLOAD),
+            None => DEFAULT_UDP_PAYLOAD,
+        }
+    }
+
+    /// Attach an OPT record advertising our own UDP payload size. A non-zero
+    /// `extended_rcode` holds the upper 8 bits of a 12 bit response code (e.g. BADVERS)
+    pub fn set_edns(&mut self, packet_len: u16, extended_rcode: u8) {
+        self.resources
+            .retain(|rec| !matches!(rec, DnsRecord::OPT { .. }));
+        self.resources.push(DnsRecord::OPT {
+            packet_len,
+            flags: (extended_rcode as u32) << 24,
+            data: Vec::new(),
+        });
+    }
+
     pub fn write(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
         self.header.questions = self.questions.len() as u16;
         self.header.answers = self.answers.len() as u16;
@@ -683,3 +1060,190 @@ impl DnsPacket {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query_with_edns(packet_len: u16, version: u8) -> Result<Vec<u8>> {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 1234;
+        packet
+            .questions
+            .push(DnsQuestion::new("aa.foo.co".to_string(), QueryType::A));
+        packet.resources.push(DnsRecord::OPT {
+            packet_len,
+            flags: (version as u32) << 16,
+            data: vec![0, 10, 0, 2, 0xAB, 0xCD],
+        });
+        let mut buffer = BytePacketBuffer::with_limit(DEFAULT_UDP_PAYLOAD);
+        packet.write(&mut buffer)?;
+        Ok(buffer.get_data()?.to_vec())
+    }
+
+    #[test]
+    fn test_edns_round_trip() -> Result<()> {
+        let raw = query_with_edns(1232, 0)?;
+        let packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&raw))?;
+        assert_eq!(packet.questions[0].name, "aa.foo.co");
+        assert_eq!(packet.edns_payload_size(), Some(1232));
+        assert_eq!(packet.edns_version(), Some(0));
+        assert_eq!(packet.max_response_size(), 1232);
+        assert_eq!(
+            packet.resources[0],
+            DnsRecord::OPT {
+                packet_len: 1232,
+                flags: 0,
+                data: vec![0, 10, 0, 2, 0xAB, 0xCD]
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_qname_case_preserved() -> Result<()> {
+        let mut packet = DnsPacket::new();
+        packet
+            .questions
+            .push(DnsQuestion::new("AaDdPmRg.I.mDp.iM".to_string(), QueryType::A));
+        let mut buffer = BytePacketBuffer::with_limit(DEFAULT_UDP_PAYLOAD);
+        packet.write(&mut buffer)?;
+        let raw = buffer.get_data()?.to_vec();
+        let packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&raw))?;
+        assert_eq!(packet.questions[0].name, "AaDdPmRg.I.mDp.iM");
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_response_size() -> Result<()> {
+        let small = query_with_edns(100, 0)?;
+        let packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&small))?;
+        assert_eq!(packet.max_response_size(), DEFAULT_UDP_PAYLOAD);
+
+        let large = query_with_edns(65000, 1)?;
+        let packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&large))?;
+        assert_eq!(packet.max_response_size(), MAX_UDP_PAYLOAD);
+        assert_eq!(packet.edns_version(), Some(1));
+
+        let mut plain = DnsPacket::new();
+        plain
+            .questions
+            .push(DnsQuestion::new("aa.foo.co".to_string(), QueryType::A));
+        let mut buffer = BytePacketBuffer::with_limit(DEFAULT_UDP_PAYLOAD);
+        plain.write(&mut buffer)?;
+        let raw = buffer.get_data()?.to_vec();
+        let packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&raw))?;
+        assert_eq!(packet.edns_payload_size(), None);
+        assert_eq!(packet.max_response_size(), DEFAULT_UDP_PAYLOAD);
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffer_grows_to_limit() -> Result<()> {
+        let mut packet = DnsPacket::new();
+        for i in 0..40 {
+            packet.answers.push(DnsRecord::A {
+                domain: "aaaaaaaaaaaaaaaa.foo.co".to_string(),
+                addr: Ipv4Addr::new(10, 0, 0, i),
+                ttl: 60,
+            });
+        }
+        let err = packet.write(&mut BytePacketBuffer::with_limit(DEFAULT_UDP_PAYLOAD)).unwrap_err();
+        assert!(err.is::<BufferFull>());
+
+        let mut buffer = BytePacketBuffer::with_limit(MAX_UDP_PAYLOAD);
+        packet.write(&mut buffer)?;
+        assert!(buffer.get_data()?.len() > DEFAULT_UDP_PAYLOAD);
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_round_trip() -> Result<()> {
+        let records = vec![
+            DnsRecord::SOA {
+                domain: "foo.co".to_string(),
+                m_name: "ns1.foo.co".to_string(),
+                r_name: "hostmaster.foo.co".to_string(),
+                serial: 2021031501,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 60,
+                ttl: 300,
+            },
+            DnsRecord::NULL {
+                domain: "null.foo.co".to_string(),
+                data: vec![0, 1, 2, 0xFF],
+                ttl: 0,
+            },
+            DnsRecord::PTR {
+                domain: "1.0.0.10.in-addr.arpa".to_string(),
+                host: "device.foo.co".to_string(),
+                ttl: 60,
+            },
+            DnsRecord::TXT {
+                domain: "txt.foo.co".to_string(),
+                data: vec![b"v=1".to_vec(), Vec::new(), vec![b'a'; 255]],
+                ttl: 60,
+            },
+            DnsRecord::SRV {
+                domain: "_dns._udp.foo.co".to_string(),
+                priority: 10,
+                weight: 5,
+                port: 53,
+                host: "ns1.foo.co".to_string(),
+                ttl: 60,
+            },
+            DnsRecord::UNKNOWN {
+                domain: "caa.foo.co".to_string(),
+                qtype: 257,
+                data: vec![0, 5, b'i', b's', b's', b'u', b'e'],
+                ttl: 60,
+            },
+        ];
+        let mut packet = DnsPacket::new();
+        packet.answers = records.clone();
+        let mut buffer = BytePacketBuffer::with_limit(MAX_UDP_PAYLOAD);
+        packet.write(&mut buffer)?;
+        let raw = buffer.get_data()?.to_vec();
+        let packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&raw))?;
+        assert_eq!(packet.answers, records);
+        Ok(())
+    }
+
+    #[test]
+    fn test_txt_string_too_long() {
+        let record = DnsRecord::TXT {
+            domain: "txt.foo.co".to_string(),
+            data: vec![vec![b'a'; 256]],
+            ttl: 60,
+        };
+        let mut buffer = BytePacketBuffer::with_limit(MAX_UDP_PAYLOAD);
+        assert!(record.write(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn test_truncate() -> Result<()> {
+        let mut packet = DnsPacket::new();
+        packet
+            .questions
+            .push(DnsQuestion::new("aa.foo.co".to_string(), QueryType::A));
+        packet.answers.push(DnsRecord::A {
+            domain: "aa.foo.co".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: 60,
+        });
+        packet.set_edns(1232, 0);
+        packet.truncate();
+
+        let mut buffer = BytePacketBuffer::with_limit(DEFAULT_UDP_PAYLOAD);
+        packet.write(&mut buffer)?;
+        let raw = buffer.get_data()?.to_vec();
+        let packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&raw))?;
+        assert!(packet.header.truncated_message);
+        assert_eq!(packet.questions.len(), 1);
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.edns_payload_size(), Some(1232));
+        Ok(())
+    }
+}
@@ -0,0 +1,39 @@
+use std::sync::mpsc::Sender;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::geolocation::Location;
+use crate::places::PlaceMatch;
+
+pub const MESSAGE_COMPLETED: &str = "message.completed";
+// Sent again with the location once a scan has been geolocated
+pub const MESSAGE_LOCATED: &str = "message.located";
+
+/// A completed message or its fix, as published to webhooks and MQTT.
+/// `event` is MESSAGE_COMPLETED or MESSAGE_LOCATED.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageEvent {
+    pub event: &'static str,
+    pub device_id: String,
+    pub message_id: String,
+    pub zone: String,
+    pub first_chunk_at: String,
+    pub last_chunk_at: String,
+    // The decoded scan, or the text of a text message
+    pub payload: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub place: Option<PlaceMatch>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+}
+
+/// Hand `event` to every worker publishing events
+pub fn send_event(workers: &[Sender<MessageEvent>], event: &MessageEvent) {
+    for worker in workers {
+        if worker.send(event.clone()).is_err() {
+            eprintln!("[{}] Unable to publish {} of {}, a worker has stopped", event.zone, event.event, event.message_id);
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use std::fmt::Write;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::time::SystemTime;
+
+use serde_json::json;
+
+use crate::storage::StoredFix;
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+const CSV_HEADER: &str = "time,device_id,message_id,lat,lng,accuracy_m,ssid,access_points,place,boot_counter,resolver,zone";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    GeoJson,
+    Gpx,
+    Kml,
+    Csv,
+}
+
+impl ExportFormat {
+    /// Accepts the format's usual file extension, in any case
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "geojson" | "json" => Some(ExportFormat::GeoJson),
+            "gpx" => Some(ExportFormat::Gpx),
+            "kml" => Some(ExportFormat::Kml),
+            "csv" => Some(ExportFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// Parse the bounds of a time range, given in RFC 3339
+pub fn parse_time(time: &str) -> Result<SystemTime> {
+    DateTime::parse_from_rfc3339(time)
+        .map(SystemTime::from)
+        .map_err(|e| format!("Invalid time {}, expected RFC 3339 such as 2024-05-01T09:00:00Z: {}", time, e).into())
+}
+
+/// RFC 3339 in UTC to the second, as every format here expects
+pub fn format_time(at: SystemTime) -> String {
+    DateTime::<Utc>::from(at).to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Render a device's fixes, oldest first, for GIS and ops tools
+pub fn export(format: ExportFormat, device_id: &str, fixes: &[StoredFix]) -> Result<String> {
+    match format {
+        ExportFormat::GeoJson => geojson(fixes),
+        ExportFormat::Gpx => gpx(device_id, fixes),
+        ExportFormat::Kml => kml(device_id, fixes),
+        ExportFormat::Csv => csv(fixes),
+    }
+}
+
+fn timestamp(fix: &StoredFix) -> String {
+    format_time(fix.received_at)
+}
+
+// What the scan behind a fix looked like, for formats with only free text
+fn describe(fix: &StoredFix) -> String {
+    let mut description = format!("Within {:.0}m, from {} networks", fix.location.accuracy, fix.access_points);
+    if let Some(ssid) = &fix.ssid {
+        write!(description, " sent via '{}'", ssid).unwrap();
+    }
+    if let Some(place) = &fix.place {
+        write!(description, " at {}", place).unwrap();
+    }
+    write!(description, ", message {}", fix.message_id).unwrap();
+    description
+}
+
+fn geojson(fixes: &[StoredFix]) -> Result<String> {
+    let features: Vec<_> = fixes.iter()
+        .map(|fix| json!({
+            "type": "Feature",
+            // GeoJSON puts longitude first (RFC 7946 3.1.1)
+            "geometry": {"type": "Point", "coordinates": [fix.location.lng, fix.location.lat]},
+            "properties": {
+                "time": timestamp(fix),
+                "deviceId": fix.device_id,
+                "messageId": fix.message_id,
+                "accuracy": fix.location.accuracy,
+                "ssid": fix.ssid,
+                "accessPoints": fix.access_points,
+                "place": fix.place,
+                "bootCounter": fix.boot_counter,
+                "resolver": fix.resolver,
+                "zone": fix.zone,
+            },
+        }))
+        .collect();
+    Ok(serde_json::to_string_pretty(&json!({"type": "FeatureCollection", "features": features}))?)
+}
+
+fn gpx(device_id: &str, fixes: &[StoredFix]) -> Result<String> {
+    let mut gpx = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    gpx.push_str("<gpx version=\"1.1\" creator=\"dns_drop\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n");
+    writeln!(gpx, "  <trk>\n    <name>{}</name>\n    <trkseg>", xml_escape(device_id))?;
+    for fix in fixes {
+        writeln!(gpx, "      <trkpt lat=\"{}\" lon=\"{}\">", fix.location.lat, fix.location.lng)?;
+        writeln!(gpx, "        <time>{}</time>", timestamp(fix))?;
+        writeln!(gpx, "        <name>{}</name>", xml_escape(&fix.message_id))?;
+        // GPX has nowhere for a radius, so it goes in with the rest
+        writeln!(gpx, "        <desc>{}</desc>", xml_escape(&describe(fix)))?;
+        gpx.push_str("      </trkpt>\n");
+    }
+    gpx.push_str("    </trkseg>\n  </trk>\n</gpx>\n");
+    Ok(gpx)
+}
+
+fn kml(device_id: &str, fixes: &[StoredFix]) -> Result<String> {
+    let mut kml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    kml.push_str("<kml xmlns=\"http://www.opengis.net/kml/2.2\">\n");
+    writeln!(kml, "  <Document>\n    <name>{}</name>", xml_escape(device_id))?;
+    for fix in fixes {
+        let data = [
+            ("messageId", Some(fix.message_id.clone())),
+            ("accuracy", Some(fix.location.accuracy.to_string())),
+            ("ssid", fix.ssid.clone()),
+            ("accessPoints", Some(fix.access_points.to_string())),
+            ("place", fix.place.clone()),
+            ("bootCounter", fix.boot_counter.map(|boot_counter| boot_counter.to_string())),
+            ("resolver", fix.resolver.clone()),
+            ("zone", Some(fix.zone.clone())),
+        ];
+        kml.push_str("    <Placemark>\n");
+        writeln!(kml, "      <name>{}</name>", xml_escape(fix.place.as_deref().unwrap_or(&timestamp(fix))))?;
+        writeln!(kml, "      <description>{}</description>", xml_escape(&describe(fix)))?;
+        writeln!(kml, "      <TimeStamp><when>{}</when></TimeStamp>", timestamp(fix))?;
+        kml.push_str("      <ExtendedData>\n");
+        for (name, value) in data.iter().filter_map(|(name, value)| Some((name, value.as_ref()?))) {
+            writeln!(kml, "        <Data name=\"{}\"><value>{}</value></Data>", name, xml_escape(value))?;
+        }
+        kml.push_str("      </ExtendedData>\n");
+        writeln!(kml, "      <Point><coordinates>{},{}</coordinates></Point>", fix.location.lng, fix.location.lat)?;
+        kml.push_str("    </Placemark>\n");
+    }
+    kml.push_str("  </Document>\n</kml>\n");
+    Ok(kml)
+}
+
+fn csv(fixes: &[StoredFix]) -> Result<String> {
+    let mut csv = format!("{}\n", CSV_HEADER);
+    for fix in fixes {
+        let fields = [
+            timestamp(fix),
+            fix.device_id.clone(),
+            fix.message_id.clone(),
+            fix.location.lat.to_string(),
+            fix.location.lng.to_string(),
+            fix.location.accuracy.to_string(),
+            fix.ssid.clone().unwrap_or_default(),
+            fix.access_points.to_string(),
+            fix.place.clone().unwrap_or_default(),
+            fix.boot_counter.map(|boot_counter| boot_counter.to_string()).unwrap_or_default(),
+            fix.resolver.clone().unwrap_or_default(),
+            fix.zone.clone(),
+        ];
+        let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        writeln!(csv, "{}", fields.join(","))?;
+    }
+    Ok(csv)
+}
+
+// Quote fields which would otherwise break the row (RFC 4180)
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geolocation::Location;
+    use std::time::Duration;
+
+    fn fixes() -> Vec<StoredFix> {
+        vec![
+            StoredFix {
+                message_id: "ZACKAAAAQX2MD".to_string(),
+                device_id: "ZACKAAAA".to_string(),
+                boot_counter: Some(3),
+                zone: "i.mdp.im".to_string(),
+                resolver: Some("192.0.2.53:5353".to_string()),
+                received_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+                ssid: Some("Cafe \"Wi-Fi\", <free>".to_string()),
+                access_points: 10,
+                place: None,
+                location: Location { lat: 51.5, lng: -0.12, accuracy: 25.0, ellipse: None },
+            },
+            StoredFix {
+                message_id: "ZACKAAAARRRRE".to_string(),
+                device_id: "ZACKAAAA".to_string(),
+                boot_counter: Some(4),
+                zone: "i.mdp.im".to_string(),
+                resolver: None,
+                received_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_600),
+                ssid: None,
+                access_points: 3,
+                place: Some("depot 3".to_string()),
+                location: Location { lat: 51.51, lng: -0.13, accuracy: 40.0, ellipse: None },
+            },
+        ]
+    }
+
+    #[test]
+    fn test_format_names() {
+        assert_eq!(ExportFormat::parse("GeoJSON"), Some(ExportFormat::GeoJson));
+        assert_eq!(ExportFormat::parse("kml"), Some(ExportFormat::Kml));
+        assert_eq!(ExportFormat::parse("shp"), None);
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("2020-09-13T13:26:40+01:00").unwrap(), SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        assert!(parse_time("2020-09-13").is_err());
+    }
+
+    #[test]
+    fn test_geojson() -> Result<()> {
+        let collection: serde_json::Value = serde_json::from_str(&export(ExportFormat::GeoJson, "ZACKAAAA", &fixes())?)?;
+        assert_eq!(collection["type"], "FeatureCollection");
+        assert_eq!(collection["features"][0]["geometry"]["coordinates"], json!([-0.12, 51.5]));
+        assert_eq!(collection["features"][0]["properties"]["time"], "2020-09-13T12:26:40Z");
+        assert_eq!(collection["features"][0]["properties"]["accuracy"], 25.0);
+        assert_eq!(collection["features"][1]["properties"]["place"], "depot 3");
+        Ok(())
+    }
+
+    #[test]
+    fn test_xml_formats() -> Result<()> {
+        let gpx = export(ExportFormat::Gpx, "ZACKAAAA", &fixes())?;
+        assert!(gpx.contains("<trkpt lat=\"51.5\" lon=\"-0.12\">"));
+        assert!(gpx.contains("<time>2020-09-13T12:36:40Z</time>"));
+        assert!(gpx.contains("Within 25m, from 10 networks sent via &apos;Cafe &quot;Wi-Fi&quot;, &lt;free&gt;&apos;"));
+        assert_eq!(gpx.matches("<trkpt").count(), 2);
+
+        let kml = export(ExportFormat::Kml, "ZACKAAAA", &fixes())?;
+        assert!(kml.contains("<TimeStamp><when>2020-09-13T12:26:40Z</when></TimeStamp>"));
+        assert!(kml.contains("<coordinates>-0.13,51.51</coordinates>"));
+        assert!(kml.contains("<Data name=\"accuracy\"><value>40</value></Data>"));
+        assert!(kml.contains("<name>depot 3</name>"));
+        // Left out rather than empty
+        assert_eq!(kml.matches("<Data name=\"resolver\">").count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_csv() -> Result<()> {
+        let csv = export(ExportFormat::Csv, "ZACKAAAA", &fixes())?;
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(lines[1], "2020-09-13T12:26:40Z,ZACKAAAA,ZACKAAAAQX2MD,51.5,-0.12,25,\"Cafe \"\"Wi-Fi\"\", <free>\",10,,3,192.0.2.53:5353,i.mdp.im");
+        assert_eq!(lines[2], "2020-09-13T12:36:40Z,ZACKAAAA,ZACKAAAARRRRE,51.51,-0.13,40,,3,depot 3,4,,i.mdp.im");
+        Ok(())
+    }
+}
@@ -0,0 +1,265 @@
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+
+use crate::geolocation::{Geolocator, Location};
+use crate::payload::{MacAddress, WifiScan};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+// How many of the strongest APs make up a fingerprint
+const FINGERPRINT_APS: usize = 5;
+// Share of APs two fingerprints need in common to count as the same spot,
+// 4 out of 5 gives 4/6
+const DEFAULT_SIMILARITY: f64 = 0.6;
+const DEFAULT_CAPACITY: usize = 4096;
+// APs do get moved and replaced, look places up again now and then
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The strongest BSSIDs of a scan, sorted so the order they were heard in
+/// doesn't matter
+pub type Fingerprint = Vec<MacAddress>;
+
+fn fingerprint(scan: &WifiScan) -> Fingerprint {
+    let mut access_points = scan.access_points.clone();
+    access_points.sort_by_key(|ap| std::cmp::Reverse(ap.rssi));
+    let mut fingerprint: Fingerprint = access_points.iter()
+        .take(FINGERPRINT_APS)
+        .map(|ap| ap.mac_address)
+        .collect();
+    fingerprint.sort_unstable_by_key(|mac| mac.0);
+    fingerprint.dedup();
+    fingerprint
+}
+
+/// Jaccard index of two fingerprints, 1 when they hold the same APs
+fn similarity(a: &Fingerprint, b: &Fingerprint) -> f64 {
+    let shared = a.iter().filter(|mac| b.contains(mac)).count();
+    let union = a.len() + b.len() - shared;
+    if union == 0 {
+        return 0.0;
+    }
+    shared as f64 / union as f64
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedFix {
+    location: Location,
+    // Unix time the fix was looked up
+    at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    fingerprint: Fingerprint,
+    #[serde(flatten)]
+    fix: CachedFix,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheFile {
+    // Least recently used first
+    entries: Vec<CacheEntry>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct GeoCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+/// Fixes of scans seen before, so a device sitting still doesn't cost a
+/// lookup on every wake
+#[derive(Debug)]
+pub struct GeoCache {
+    fixes: LruCache<Fingerprint, CachedFix>,
+    similarity: f64,
+    max_age: Duration,
+    path: Option<PathBuf>,
+    hits: u64,
+    misses: u64,
+}
+
+impl GeoCache {
+    pub fn new() -> Self {
+        GeoCache {
+            fixes: LruCache::new(NonZeroUsize::new(DEFAULT_CAPACITY).unwrap()),
+            similarity: DEFAULT_SIMILARITY,
+            max_age: DEFAULT_MAX_AGE,
+            path: None,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Share of APs a scan needs in common with a cached one to reuse its
+    /// fix. Zero would hand any cached fix to scans sharing no APs with it.
+    pub fn with_similarity(mut self, similarity: f64) -> Result<Self> {
+        if !(similarity > 0.0 && similarity <= 1.0) {
+            return Err(format!("Geolocation cache similarity must be above 0 and at most 1, not {}", similarity).into());
+        }
+        self.similarity = similarity;
+        Ok(self)
+    }
+
+    /// Keep the cache in `path`, loading what's there already
+    pub fn persist_to(mut self, path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                let cache_file: CacheFile = serde_json::from_str(&contents)
+                    .map_err(|e| format!("Invalid geolocation cache {}: {}", path.display(), e))?;
+                for entry in cache_file.entries {
+                    self.fixes.put(entry.fingerprint, entry.fix);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Unable to read geolocation cache {}: {}", path.display(), e).into()),
+        }
+        self.path = Some(path.to_path_buf());
+        Ok(self)
+    }
+
+    pub fn stats(&self) -> GeoCacheStats {
+        GeoCacheStats { hits: self.hits, misses: self.misses, entries: self.fixes.len() }
+    }
+
+    /// The fix of the most similar cached scan, if similar enough
+    pub fn lookup(&mut self, scan: &WifiScan) -> Option<Location> {
+        let fingerprint = fingerprint(scan);
+        let oldest = unix_secs(SystemTime::now()).saturating_sub(self.max_age.as_secs());
+        let best = self.fixes.iter()
+            .filter(|(_, fix)| fix.at >= oldest)
+            .map(|(cached, _)| (similarity(&fingerprint, cached), cached))
+            .filter(|(similarity, _)| *similarity >= self.similarity)
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, cached)| cached.clone());
+        match best {
+            Some(cached) => {
+                self.hits += 1;
+                // Promotes the fix to most recently used
+                self.fixes.get(&cached).map(|fix| fix.location.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, scan: &WifiScan, location: &Location) {
+        let fix = CachedFix { location: location.clone(), at: unix_secs(SystemTime::now()) };
+        self.fixes.put(fingerprint(scan), fix);
+    }
+
+    /// Write the cache out if it's persisted, replacing the file in one go
+    pub fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let cache_file = CacheFile {
+            entries: self.fixes.iter().rev()
+                .map(|(fingerprint, fix)| CacheEntry { fingerprint: fingerprint.clone(), fix: fix.clone() })
+                .collect(),
+        };
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_string(&cache_file)?)
+            .map_err(|e| format!("Unable to write to {}: {}", tmp_path.display(), e))?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+fn unix_secs(at: SystemTime) -> u64 {
+    at.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Answers from the cache where it can, asking `inner` otherwise
+pub struct CachedGeolocator {
+    inner: Box<dyn Geolocator>,
+    cache: Arc<Mutex<GeoCache>>,
+}
+
+impl CachedGeolocator {
+    pub fn new(inner: Box<dyn Geolocator>, cache: Arc<Mutex<GeoCache>>) -> Self {
+        CachedGeolocator { inner, cache }
+    }
+}
+
+impl Geolocator for CachedGeolocator {
+    fn locate(&self, scan: &WifiScan) -> Result<Location> {
+        let mut cache = self.cache.lock().unwrap();
+        let cached = cache.lookup(scan);
+        drop(cache);
+        if let Some(location) = cached {
+            return Ok(location);
+        }
+        // Not holding the lock while waiting on the provider
+        let location = self.inner.locate(scan)?;
+        let mut cache = self.cache.lock().unwrap();
+        cache.insert(scan, &location);
+        if let Err(e) = cache.save() {
+            eprintln!("Unable to save geolocation cache: {}", e);
+        }
+        Ok(location)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{scan, TempPath};
+
+    // Puts every scan it's asked about somewhere new
+    struct CountingGeolocator(Mutex<u32>);
+
+    impl Geolocator for CountingGeolocator {
+        fn locate(&self, _scan: &WifiScan) -> Result<Location> {
+            let mut calls = self.0.lock().unwrap();
+            *calls += 1;
+            Ok(Location { lat: *calls as f64, lng: 0.0, accuracy: 20.0, ellipse: None })
+        }
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        // Only the five strongest count, in no particular order
+        let a = scan(&[(1, -50), (2, -60), (3, -55), (4, -70), (5, -65), (6, -90)]);
+        let b = scan(&[(5, -66), (4, -71), (3, -54), (2, -61), (1, -49), (7, -91)]);
+        assert_eq!(fingerprint(&a), fingerprint(&b));
+        assert_eq!(fingerprint(&a).len(), 5);
+        assert!((similarity(&fingerprint(&a), &fingerprint(&scan(&[(1, -50), (2, -50), (3, -50), (4, -50), (8, -50)]))) - 4.0 / 6.0).abs() < 1e-9);
+        assert_eq!(similarity(&vec![], &vec![]), 0.0);
+    }
+
+    #[test]
+    fn test_cached_geolocator() -> Result<()> {
+        let path = TempPath::new("geo_cache.json");
+        let cache = Arc::new(Mutex::new(GeoCache::new().persist_to(&path)?));
+        let geolocator = CachedGeolocator::new(Box::new(CountingGeolocator(Mutex::new(0))), cache.clone());
+
+        let home = scan(&[(1, -50), (2, -60), (3, -55), (4, -70), (5, -65)]);
+        assert_eq!(geolocator.locate(&home)?.lat, 1.0);
+        // One AP swapped and the signals shifted a little, still home
+        assert_eq!(geolocator.locate(&scan(&[(1, -52), (2, -58), (3, -55), (4, -71), (9, -64)]))?.lat, 1.0);
+        // Somewhere else
+        assert_eq!(geolocator.locate(&scan(&[(1, -80), (10, -50), (11, -50), (12, -50), (13, -50)]))?.lat, 2.0);
+        assert_eq!(cache.lock().unwrap().stats(), GeoCacheStats { hits: 1, misses: 2, entries: 2 });
+
+        // Survives a restart
+        let mut reloaded = GeoCache::new().persist_to(&path)?;
+        assert_eq!(reloaded.stats().entries, 2);
+        assert_eq!(reloaded.lookup(&home).map(|location| location.lat), Some(1.0));
+        assert_eq!(reloaded.with_similarity(1.0)?.lookup(&scan(&[(1, -50), (2, -60)])), None);
+        assert!(GeoCache::new().with_similarity(0.0).is_err());
+        assert!(GeoCache::new().with_similarity(1.5).is_err());
+        assert!(GeoCache::new().with_similarity(f64::NAN).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::payload::WifiScan;
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+pub const GOOGLE_API_URL: &str = "https://www.googleapis.com";
+const GEOLOCATE_PATH: &str = "/geolocation/v1/geolocate";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+pub const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+/// Where a scan was taken, `accuracy` being the radius in metres of the
+/// circle it's likely within
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    pub lat: f64,
+    pub lng: f64,
+    pub accuracy: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ellipse: Option<ErrorEllipse>,
+}
+
+/// The area a position is likely within, when it's better known in some
+/// directions than others
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorEllipse {
+    pub semi_major: f64,
+    pub semi_minor: f64,
+    // Direction of the major axis in degrees clockwise from north, 0 to 180
+    pub orientation: f64,
+}
+
+impl Location {
+    pub fn maps_url(&self) -> String {
+        format!("https://www.google.com/maps/search/?api=1&query={},{}", self.lat, self.lng)
+    }
+}
+
+/// Great circle distance in metres between two points given in degrees
+pub fn distance_m(lat1: f64, lng1: f64, lat2: f64, lng2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lng = (lng2 - lng1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lng / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * a.sqrt().asin()
+}
+
+/// Turns the networks seen in a scan into a position
+pub trait Geolocator: Send {
+    fn locate(&self, scan: &WifiScan) -> Result<Location>;
+}
+
+// Response bodies of the geolocate call, either a fix or an error
+#[derive(Debug, Deserialize)]
+struct GoogleLocation {
+    lat: f64,
+    lng: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct GoogleResponse {
+    location: GoogleLocation,
+    accuracy: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct GoogleError {
+    code: u16,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GoogleErrorResponse {
+    error: GoogleError,
+}
+
+/// Client for the Google Geolocation API, or anything speaking the same
+/// request and response format
+pub struct GoogleGeolocator {
+    base_url: String,
+    api_key: Option<String>,
+    agent: ureq::Agent,
+}
+
+impl GoogleGeolocator {
+    pub fn new(base_url: &str, api_key: Option<String>) -> Self {
+        GoogleGeolocator {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            agent: ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build(),
+        }
+    }
+
+    /// Request body as in geo_sample.json. Only the networks seen count, the
+    /// address we'd be calling from says nothing about the device.
+    fn request_body(scan: &WifiScan) -> serde_json::Value {
+        json!({
+            "considerIp": false,
+            "wifiAccessPoints": scan.access_points,
+        })
+    }
+}
+
+impl Geolocator for GoogleGeolocator {
+    fn locate(&self, scan: &WifiScan) -> Result<Location> {
+        let mut request = self.agent.post(&format!("{}{}", self.base_url, GEOLOCATE_PATH));
+        if let Some(api_key) = &self.api_key {
+            request = request.query("key", api_key);
+        }
+        let body = Self::request_body(scan).to_string();
+        match request.set("Content-Type", "application/json").send_string(&body) {
+            Ok(response) => {
+                let response: GoogleResponse = serde_json::from_str(&response.into_string()?)?;
+                Ok(Location {
+                    lat: response.location.lat,
+                    lng: response.location.lng,
+                    accuracy: response.accuracy,
+                    ellipse: None,
+                })
+            }
+            Err(ureq::Error::Status(status, response)) => {
+                let body = response.into_string().unwrap_or_default();
+                match serde_json::from_str::<GoogleErrorResponse>(&body) {
+                    Ok(error) => Err(format!("Geolocation failed ({}): {}", error.error.code, error.error.message).into()),
+                    Err(_) => Err(format!("Geolocation failed ({})", status).into()),
+                }
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::http_stand_in;
+
+    #[test]
+    fn test_locate() -> Result<()> {
+        let (base_url, requests) = http_stand_in(&[(
+            "200 OK",
+            r#"{"location": {"lat": 37.7749, "lng": -122.4194}, "accuracy": 35.5}"#,
+        )]);
+        let geolocator = GoogleGeolocator::new(&format!("{}/", base_url), Some("secret".to_string()));
+        let scan = WifiScan::decode(include_bytes!("../sample_log.bin"))?;
+        let location = geolocator.locate(&scan)?;
+        assert_eq!(location, Location { lat: 37.7749, lng: -122.4194, accuracy: 35.5, ellipse: None });
+
+        let request = requests.recv()?;
+        assert_eq!(request.request_line, "POST /geolocation/v1/geolocate?key=secret HTTP/1.1");
+        let request: serde_json::Value = serde_json::from_str(&request.body)?;
+        assert_eq!(request["considerIp"], false);
+        assert_eq!(request["wifiAccessPoints"].as_array().unwrap().len(), 10);
+        assert_eq!(
+            request["wifiAccessPoints"][1],
+            json!({"macAddress": "02:11:32:68:43:A2", "signalStrength": -73, "channel": 8})
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_locate_not_found() -> Result<()> {
+        let (base_url, requests) = http_stand_in(&[(
+            "404 Not Found",
+            r#"{"error": {"errors": [{"domain": "geolocation", "reason": "notFound", "message": "Not Found"}], "code": 404, "message": "Not Found"}}"#,
+        )]);
+        let geolocator = GoogleGeolocator::new(&base_url, None);
+        let scan = WifiScan::decode(include_bytes!("../sample_log.bin"))?;
+        let error = geolocator.locate(&scan).unwrap_err();
+        assert_eq!(error.to_string(), "Geolocation failed (404): Not Found");
+        assert_eq!(requests.recv()?.request_line, "POST /geolocation/v1/geolocate HTTP/1.1");
+        Ok(())
+    }
+}
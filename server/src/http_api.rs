@@ -0,0 +1,323 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::export::{format_time, parse_time};
+use crate::geo_cache::GeoCache;
+use crate::metrics::Metrics;
+use crate::payload::WifiScan;
+use crate::storage::{MessageRecord, Storage, StoredFix};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// A request line and a few headers, nothing we serve takes a body
+const MAX_REQUEST_HEAD: u64 = 8192;
+// Prometheus text exposition format
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// What the API serves from
+pub struct Api {
+    // Should be a connection of its own, so queries never hold up messages
+    // being recorded. Only /metrics is served without one.
+    pub storage: Option<Mutex<Storage>>,
+    pub metrics: Arc<Metrics>,
+    pub geo_cache: Option<Arc<Mutex<GeoCache>>>,
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, value: &Value) -> Self {
+        Response { status, content_type: "application/json", body: value.to_string().into_bytes() }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Response::json(status, &json!({"error": message}))
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Answer API requests on `listener`, a thread per connection like DNS over
+/// TCP
+pub fn serve(listener: TcpListener, api: Arc<Api>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let api = Arc::clone(&api);
+                thread::spawn(move || {
+                    if let Err(e) = handle_connection(stream, &api) {
+                        eprintln!("HTTP API error: {}", e);
+                    }
+                });
+            }
+            Err(e) => eprintln!("HTTP API error: {}", e),
+        }
+    }
+}
+
+/// Read one request and answer it, closing the connection after
+fn handle_connection(mut stream: TcpStream, api: &Api) -> Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST_HEAD));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let response = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        [method, target, _version] => route(method, target, api),
+        _ => Response::error(400, "Malformed request line"),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status, reason_phrase(response.status), response.content_type, response.body.len()
+    )?;
+    stream.write_all(&response.body)?;
+    Ok(())
+}
+
+/// `GET /metrics`, and with a database `/devices`, `/devices/ID/latest`,
+/// `/devices/ID/track?from=TIME&to=TIME`, `/messages/ID` and `/messages/ID/raw`
+pub fn route(method: &str, target: &str, api: &Api) -> Response {
+    if method != "GET" {
+        return Response::error(405, "Only GET is supported");
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if path == "/metrics" {
+        let geo_cache = api.geo_cache.as_ref().map(|geo_cache| geo_cache.lock().unwrap().stats());
+        return Response { status: 200, content_type: METRICS_CONTENT_TYPE, body: api.metrics.render(geo_cache).into_bytes() };
+    }
+    let storage = match &api.storage {
+        Some(storage) => storage.lock().unwrap(),
+        None => return Response::error(404, "No such endpoint, there is no database to serve"),
+    };
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let response = match segments[..] {
+        ["devices"] => devices(&storage),
+        ["devices", device_id, "latest"] => latest_fix(&storage, device_id),
+        ["devices", device_id, "track"] => track(&storage, device_id, query),
+        ["messages", message_id] => message(&storage, message_id),
+        ["messages", message_id, "raw"] => raw_message(&storage, message_id),
+        _ => Ok(Response::error(404, "No such endpoint")),
+    };
+    response.unwrap_or_else(|e| Response::error(500, &e.to_string()))
+}
+
+fn devices(storage: &Storage) -> Result<Response> {
+    let devices: Vec<Value> = storage.devices()?.iter()
+        .map(|device| json!({
+            "id": device.id,
+            "firstSeen": format_time(device.first_seen),
+            "lastSeen": format_time(device.last_seen),
+            "messages": device.messages,
+        }))
+        .collect();
+    Ok(Response::json(200, &json!(devices)))
+}
+
+fn latest_fix(storage: &Storage, device_id: &str) -> Result<Response> {
+    Ok(match storage.latest_fix(device_id)? {
+        Some(fix) => Response::json(200, &fix_json(&fix)),
+        None => Response::error(404, &format!("No fixes of {}", device_id)),
+    })
+}
+
+fn track(storage: &Storage, device_id: &str, query: &str) -> Result<Response> {
+    let mut bounds = [None, None];
+    for (bound, name) in bounds.iter_mut().zip(["from", "to"]) {
+        if let Some(time) = query_param(query, name) {
+            match parse_time(&time) {
+                Ok(time) => *bound = Some(time),
+                Err(e) => return Ok(Response::error(400, &e.to_string())),
+            }
+        }
+    }
+    let fixes: Vec<Value> = storage.fixes(device_id, bounds[0], bounds[1])?.iter().map(fix_json).collect();
+    Ok(Response::json(200, &json!(fixes)))
+}
+
+fn message(storage: &Storage, message_id: &str) -> Result<Response> {
+    Ok(match storage.message(message_id)? {
+        Some(message) => Response::json(200, &message_json(&message)),
+        None => Response::error(404, &format!("No message {}", message_id)),
+    })
+}
+
+fn raw_message(storage: &Storage, message_id: &str) -> Result<Response> {
+    Ok(match storage.message(message_id)? {
+        Some(message) => Response { status: 200, content_type: "application/octet-stream", body: message.raw },
+        None => Response::error(404, &format!("No message {}", message_id)),
+    })
+}
+
+fn fix_json(fix: &StoredFix) -> Value {
+    json!({
+        "time": format_time(fix.received_at),
+        "messageId": fix.message_id,
+        "lat": fix.location.lat,
+        "lng": fix.location.lng,
+        "accuracy": fix.location.accuracy,
+        "ellipse": fix.location.ellipse,
+        "ssid": fix.ssid,
+        "accessPoints": fix.access_points,
+        "place": fix.place,
+        "bootCounter": fix.boot_counter,
+        "resolver": fix.resolver,
+        "zone": fix.zone,
+    })
+}
+
+fn message_json(message: &MessageRecord) -> Value {
+    let raw_hex: String = message.raw.iter().map(|byte| format!("{:02x}", byte)).collect();
+    json!({
+        "messageId": message.message_id,
+        "deviceId": message.device_id,
+        "bootCounter": message.boot_counter,
+        "zone": message.zone,
+        "resolver": message.resolver,
+        "firstChunkAt": format_time(message.first_chunk_at),
+        "lastChunkAt": format_time(message.last_chunk_at),
+        "raw": raw_hex,
+        "scan": WifiScan::decode(&message.raw).ok(),
+        "place": message.place,
+        "placeConfidence": message.place_confidence,
+        "location": message.location,
+    })
+}
+
+/// A query string value, with its %XX escapes decoded
+fn query_param(query: &str, name: &str) -> Option<String> {
+    let value = query.split('&').find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))?;
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        let escaped = if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+        } else {
+            byte
+        };
+        decoded.push(escaped);
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geolocation::{ErrorEllipse, Location};
+    use crate::storage::StoredMessage;
+    use crate::zone::DomainName;
+    use std::time::SystemTime;
+
+    fn api() -> Result<Api> {
+        let mut storage = Storage::open(std::path::Path::new(":memory:"))?;
+        let raw = include_bytes!("../sample_log.bin");
+        let scan = WifiScan::decode(raw)?;
+        let zone = DomainName::new("i.mdp.im");
+        let at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        // Only the later fix was trilaterated and has an ellipse
+        let ellipse = ErrorEllipse { semi_major: 40.0, semi_minor: 20.0, orientation: 15.0 };
+        for (message_id, offset, lat, ellipse) in [("ZACKAAAAQX2MD", 0, 51.5, None), ("ZACKAAAARRRRE", 600, 51.6, Some(ellipse))] {
+            let row = storage.record_message(&StoredMessage {
+                message_id,
+                zone: &zone,
+                resolver: None,
+                first_chunk_at: at + Duration::from_secs(offset),
+                last_chunk_at: at + Duration::from_secs(offset),
+                raw,
+                scan: Some(&scan),
+                place: None,
+            })?;
+            storage.record_location(row, &Location { lat, lng: -0.12, accuracy: 20.0, ellipse })?;
+        }
+        Ok(Api { storage: Some(Mutex::new(storage)), metrics: Arc::new(Metrics::new()), geo_cache: None })
+    }
+
+    fn get_json(target: &str, api: &Api) -> (u16, Value) {
+        let response = route("GET", target, api);
+        (response.status, serde_json::from_slice(&response.body).unwrap())
+    }
+
+    #[test]
+    fn test_routes() -> Result<()> {
+        let api = api()?;
+        let (status, devices) = get_json("/devices", &api);
+        assert_eq!((status, devices[0]["id"].as_str(), devices[0]["lastSeen"].as_str()), (200, Some("ZACKAAAA"), Some("2020-09-13T12:36:40Z")));
+
+        let (_, latest) = get_json("/devices/ZACKAAAA/latest", &api);
+        assert_eq!((latest["lat"].as_f64(), latest["ellipse"]["semi_major"].as_f64()), (Some(51.6), Some(40.0)));
+        assert_eq!(get_json("/devices/ZACKAAAB/latest", &api).0, 404);
+        assert_eq!(get_json("/devices/ZACKAAAA/track", &api).1.as_array().map(Vec::len), Some(2));
+        let (_, track) = get_json("/devices/ZACKAAAA/track?to=2020-09-13T12%3A30%3A00Z", &api);
+        assert_eq!(track.as_array().map(Vec::len), Some(1));
+        assert_eq!(track[0]["ellipse"], Value::Null);
+        assert_eq!(get_json("/devices/ZACKAAAA/track?from=yesterday", &api).0, 400);
+
+        let (_, message) = get_json("/messages/ZACKAAAAQX2MD", &api);
+        assert_eq!((message["bootCounter"].as_u64(), message["scan"]["ssid"].as_str()), (Some(3), Some("Starbucks WiFi")));
+        assert!(message["raw"].as_str().unwrap().starts_with("000a001132"));
+        assert_eq!(route("GET", "/messages/ZACKAAAAQX2MD/raw", &api).body, include_bytes!("../sample_log.bin"));
+        assert_eq!(get_json("/nothing", &api).0, 404);
+        assert_eq!(route("POST", "/devices", &api).status, 405);
+        Ok(())
+    }
+
+    #[test]
+    fn test_metrics() -> Result<()> {
+        let mut api = api()?;
+        api.metrics.message_completed(3, Duration::from_secs(4));
+        let response = route("GET", "/metrics", &api);
+        assert_eq!((response.status, response.content_type), (200, METRICS_CONTENT_TYPE));
+        assert!(String::from_utf8(response.body)?.contains("\ndns_drop_messages_completed_total 1\n"));
+
+        // Metrics don't need a database, the rest of the API does
+        api.storage = None;
+        assert_eq!(route("GET", "/metrics", &api).status, 200);
+        assert_eq!(get_json("/devices", &api).0, 404);
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let api = Arc::new(api()?);
+        thread::spawn(move || serve(listener, api));
+
+        let mut stream = TcpStream::connect(address)?;
+        stream.write_all(b"GET /devices HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        assert_eq!(serde_json::from_str::<Value>(body)?[0]["messages"], 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::message_handler::{MessageBufferCache, MessageChunk, MessageKey};
+use crate::zone::DomainName;
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+// Entries appended since the last compaction before the journal is rewritten
+// from the cache, keeping it roughly the size of the in-flight state
+const COMPACTION_THRESHOLD: usize = 10_000;
+
+/// One line of the journal. Replaying every entry in order rebuilds the
+/// message cache as it was.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum JournalEntry {
+    // A chunk added to the cache, `source` as kept by `MessageChunk`
+    Chunk { zone: String, source: String, at: u64 },
+    // A message handed out, its chunks are no longer needed
+    Completed { zone: String, id: String, at: u64 },
+    // A message which had conflicting chunks, refused from then on
+    Quarantined { zone: String, id: String, at: u64 },
+}
+
+impl JournalEntry {
+    fn chunk(message_chunk: &MessageChunk, at: SystemTime) -> Self {
+        JournalEntry::Chunk {
+            zone: message_chunk.zone.to_string(),
+            source: message_chunk.source.clone(),
+            at: unix_secs(at),
+        }
+    }
+
+    fn completed(key: &MessageKey, at: SystemTime) -> Self {
+        JournalEntry::Completed { zone: key.zone.to_string(), id: key.id.clone(), at: unix_secs(at) }
+    }
+
+    fn quarantined(key: &MessageKey, at: SystemTime) -> Self {
+        JournalEntry::Quarantined { zone: key.zone.to_string(), id: key.id.clone(), at: unix_secs(at) }
+    }
+}
+
+/// Append-only log of the chunks received and messages delivered, so
+/// partial messages survive a restart. Stored as one JSON object per line.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    appended: usize,
+}
+
+impl Journal {
+    /// Open the journal at `path`, creating it if needed, and return the
+    /// entries already in it. Lines which don't parse, e.g. one cut short by
+    /// a crash mid-write, are skipped.
+    pub fn open(path: &Path) -> Result<(Journal, Vec<JournalEntry>)> {
+        let mut entries = Vec::new();
+        match File::open(path) {
+            Ok(file) => {
+                for (number, line) in BufReader::new(file).lines().enumerate() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str(&line) {
+                        Ok(entry) => entries.push(entry),
+                        Err(e) => eprintln!("Skipping line {} of journal {}: {}", number + 1, path.display(), e),
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Unable to read journal {}: {}", path.display(), e).into()),
+        }
+        let journal = Journal {
+            path: path.to_path_buf(),
+            file: open_append(path)?,
+            appended: 0,
+        };
+        Ok((journal, entries))
+    }
+
+    pub fn record_chunk(&mut self, message_chunk: &MessageChunk) -> Result<()> {
+        self.append(&JournalEntry::chunk(message_chunk, SystemTime::now()))
+    }
+
+    pub fn record_completed(&mut self, key: &MessageKey) -> Result<()> {
+        self.append(&JournalEntry::completed(key, SystemTime::now()))
+    }
+
+    pub fn record_quarantined(&mut self, key: &MessageKey) -> Result<()> {
+        self.append(&JournalEntry::quarantined(key, SystemTime::now()))
+    }
+
+    // Written straight to the file without an fsync, which is enough to
+    // survive the process restarting but not the machine going down
+    fn append(&mut self, entry: &JournalEntry) -> Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.appended += 1;
+        Ok(())
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.appended >= COMPACTION_THRESHOLD
+    }
+
+    /// Replace the journal with just what the cache holds now, dropping
+    /// chunks of messages which were since delivered, evicted or expired
+    pub fn compact(&mut self, message_buffer_cache: &MessageBufferCache) -> Result<()> {
+        let snapshot = message_buffer_cache.snapshot();
+        let mut contents = String::new();
+        // Quarantines after the chunks, those of a quarantined message still
+        // held have to be back in the cache for it to keep them
+        let entries = snapshot.completed.iter()
+            .map(|(at, key)| JournalEntry::completed(key, *at))
+            .chain(snapshot.chunks.iter().map(|(at, chunk)| JournalEntry::chunk(chunk, *at)))
+            .chain(snapshot.quarantined.iter().map(|(at, key)| JournalEntry::quarantined(key, *at)));
+        for entry in entries {
+            contents.push_str(&serde_json::to_string(&entry)?);
+            contents.push('\n');
+        }
+
+        // Write elsewhere and rename over the journal, so a crash part way
+        // through leaves the old one in place
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, contents)
+            .map_err(|e| format!("Unable to write to {}: {}", tmp_path.display(), e))?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        self.file = open_append(&self.path)?;
+        self.appended = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Unable to open journal {}: {}", path.display(), e).into())
+}
+
+fn unix_secs(at: SystemTime) -> u64 {
+    at.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Rebuild the cache from journal entries, returning how many chunks were
+/// restored. Chunks for zones no longer served are dropped.
+pub fn replay(message_buffer_cache: &mut MessageBufferCache, entries: Vec<JournalEntry>, zones: &[DomainName]) -> usize {
+    let mut restored = 0;
+    for entry in entries {
+        match entry {
+            JournalEntry::Chunk { zone, source, at } => {
+                let zone = DomainName::new(&zone);
+                if !zones.contains(&zone) {
+                    continue;
+                }
+                match MessageChunk::from_source(source, zone) {
+                    Ok(message_chunk) => {
+                        if message_buffer_cache.restore(message_chunk, SystemTime::UNIX_EPOCH + Duration::from_secs(at)) {
+                            restored += 1;
+                        }
+                    }
+                    Err(e) => eprintln!("Skipping journal chunk: {}", e),
+                }
+            }
+            JournalEntry::Completed { zone, id, at } => {
+                let key = MessageKey { zone: DomainName::new(&zone), id };
+                message_buffer_cache.restore_completed(&key, SystemTime::UNIX_EPOCH + Duration::from_secs(at));
+            }
+            JournalEntry::Quarantined { zone, id, at } => {
+                let key = MessageKey { zone: DomainName::new(&zone), id };
+                message_buffer_cache.restore_quarantined(&key, SystemTime::UNIX_EPOCH + Duration::from_secs(at));
+            }
+        }
+    }
+    // Anything which went quiet while we were down
+    message_buffer_cache.expire();
+    restored
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempPath;
+
+    fn chunk(question: &str) -> Result<MessageChunk> {
+        MessageChunk::from(question, &"foo.co".into())
+    }
+
+    fn key(id: &str) -> MessageKey {
+        MessageKey { zone: "foo.co".into(), id: id.to_string() }
+    }
+
+    #[test]
+    fn test_replay() -> Result<()> {
+        let path = TempPath::new("replay.journal");
+        let zones = vec![DomainName::new("foo.co")];
+        {
+            let (mut journal, entries) = Journal::open(&path)?;
+            assert!(entries.is_empty());
+            journal.record_chunk(&chunk("AADDDDDDDDDDDDDDPMRGM33PEI5.foo.co")?)?;
+            journal.record_chunk(&chunk("AAZ222222222222ZPMRGM33PEI5.foo.co")?)?;
+            journal.record_chunk(&chunk("BBZ222222222222ZCEYTBOIRH2.foo.co")?)?;
+            journal.record_completed(&key("Z222222222222"))?;
+        }
+
+        let (mut journal, entries) = Journal::open(&path)?;
+        assert_eq!(entries.len(), 4);
+        let mut message_buffer_cache = MessageBufferCache::new(3);
+        assert_eq!(replay(&mut message_buffer_cache, entries, &zones), 3);
+        assert!(message_buffer_cache.pending_completed().is_empty());
+
+        // The rest of the partial message arrives after the restart, the
+        // delivered one stays delivered
+        assert!(message_buffer_cache.add(chunk("BBDDDDDDDDDDDDDDCEYTBOIRH2.foo.co")?)?);
+        assert!(message_buffer_cache.add(chunk("AAZ222222222222ZPMRGM33PEI5.foo.co")?)?);
+        assert_eq!(message_buffer_cache.take_completed(&key("Z222222222222")), None);
+        assert_eq!(String::from_utf8(message_buffer_cache.take_completed(&key("DDDDDDDDDDDDD")).unwrap())?, "{\"foo\":\"bar\"}");
+
+        // Until it's confirmed written out its chunks survive a compaction
+        journal.compact(&message_buffer_cache)?;
+        let (_, entries) = Journal::open(&path)?;
+        assert_eq!(entries.len(), 3);
+        message_buffer_cache.confirm_delivered(&key("DDDDDDDDDDDDD"));
+        journal.record_completed(&key("DDDDDDDDDDDDD"))?;
+
+        journal.compact(&message_buffer_cache)?;
+        drop(journal);
+        let (_, entries) = Journal::open(&path)?;
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|entry| matches!(entry, JournalEntry::Completed { .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn test_quarantine() -> Result<()> {
+        let path = TempPath::new("quarantine.journal");
+        let zones = vec![DomainName::new("foo.co")];
+        let mut message_buffer_cache = MessageBufferCache::new(3);
+        let (mut journal, _) = Journal::open(&path)?;
+        message_buffer_cache.add(chunk("AADDDDDDDDDDDDDDPMRGM33PEI5.foo.co")?)?;
+        assert!(message_buffer_cache.add(chunk("AADDDDDDDDDDDDDDPMRGM33PEI6.foo.co")?).is_err());
+        journal.record_quarantined(&key("DDDDDDDDDDDDD"))?;
+        journal.compact(&message_buffer_cache)?;
+        drop(journal);
+
+        // Still held, the last chunk can't complete it after a restart
+        let (_, entries) = Journal::open(&path)?;
+        assert!(matches!(entries.last(), Some(JournalEntry::Quarantined { .. })));
+        let mut message_buffer_cache = MessageBufferCache::new(3);
+        assert_eq!(replay(&mut message_buffer_cache, entries, &zones), 2);
+        assert!(message_buffer_cache.add(chunk("BBDDDDDDDDDDDDDDCEYTBOIRH2.foo.co")?).is_err());
+
+        // Evicted since, retransmissions journaled after the quarantine
+        // don't start it afresh
+        let entries = vec![
+            JournalEntry::quarantined(&key("DDDDDDDDDDDDD"), SystemTime::now()),
+            JournalEntry::chunk(&chunk("AADDDDDDDDDDDDDDPMRGM33PEI5.foo.co")?, SystemTime::now()),
+            JournalEntry::chunk(&chunk("BBDDDDDDDDDDDDDDCEYTBOIRH2.foo.co")?, SystemTime::now()),
+        ];
+        let mut message_buffer_cache = MessageBufferCache::new(3);
+        assert_eq!(replay(&mut message_buffer_cache, entries, &zones), 0);
+        assert!(message_buffer_cache.pending_completed().is_empty());
+        assert!(message_buffer_cache.add(chunk("AADDDDDDDDDDDDDDPMRGM33PEI5.foo.co")?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_undelivered_and_torn_write() -> Result<()> {
+        let path = TempPath::new("torn.journal");
+        {
+            let (mut journal, _) = Journal::open(&path)?;
+            journal.record_chunk(&chunk("AADDDDDDDDDDDDDDPMRGM33PEI5.foo.co")?)?;
+            journal.record_chunk(&chunk("BBDDDDDDDDDDDDDDCEYTBOIRH2.foo.co")?)?;
+            journal.record_chunk(&chunk("AAZ222222222222ZPMRGM33PEI5.foo.co")?)?;
+        }
+        let mut contents = std::fs::read_to_string(&path)?;
+        contents.push_str("{\"type\":\"chunk\",\"zone\":\"foo");
+        std::fs::write(&path, contents)?;
+
+        let (_, entries) = Journal::open(&path)?;
+        assert_eq!(entries.len(), 3);
+        let mut message_buffer_cache = MessageBufferCache::new(3);
+        // Chunks for zones we no longer serve are dropped
+        assert_eq!(replay(&mut message_buffer_cache, entries.clone(), &["i.mdp.im".into()]), 0);
+        assert_eq!(replay(&mut message_buffer_cache, entries, &["foo.co".into()]), 3);
+        // Completed before the restart but never written out
+        assert_eq!(message_buffer_cache.pending_completed(), vec![key("DDDDDDDDDDDDD")]);
+        Ok(())
+    }
+}
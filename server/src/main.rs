@@ -1,25 +1,83 @@
 extern crate clap;
 
-use std::{net::{UdpSocket}, time::SystemTime};
-use std::net::{Ipv4Addr};
+use std::{net::{TcpListener, TcpStream, UdpSocket}, time::{Duration, SystemTime}};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
 
 use chrono::{DateTime, Utc};
 
-use clap::{App};
+use clap::{App, SubCommand};
+use serde_json::json;
 
+mod ap_database;
+mod ap_filter;
+mod config;
 mod dns;
+mod events;
+mod export;
+mod geo_cache;
+mod geolocation;
+mod http_api;
+mod journal;
 mod message_handler;
+mod metrics;
+mod mqtt;
+mod payload;
+mod places;
+mod storage;
+#[cfg(test)]
+mod test_util;
+mod trilateration;
+mod webhooks;
+mod zone;
 
-use dns::{BytePacketBuffer, DnsPacket, DnsRecord, ResultCode};
-use message_handler::{MessageBufferCache, MessageChunk};
+use dns::{BufferFull, BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode, Transport, DEFAULT_UDP_PAYLOAD, MAX_UDP_PAYLOAD};
+use message_handler::{ChunkConflict, MessageBufferCache, MessageChunk, MessageKey};
+use ap_database::ApDatabase;
+use ap_filter::{ApFilter, FilteredAp};
+use config::{load_zones, ZoneDefaults};
+use events::{send_event, MessageEvent, MESSAGE_COMPLETED, MESSAGE_LOCATED};
+use export::ExportFormat;
+use geo_cache::{CachedGeolocator, GeoCache};
+use geolocation::{Geolocator, GoogleGeolocator, Location, GOOGLE_API_URL};
+use http_api::Api;
+use journal::Journal;
+use metrics::{Metrics, ParseError};
+use mqtt::{MqttPublisher, QoS};
+use payload::{WifiScan, WIFI_SCAN_VERSION};
+use places::Places;
+use storage::{Storage, StoredMessage};
+use trilateration::{Calibration, Trilaterator};
+use webhooks::{Webhook, WebhookQueue};
+use zone::{find_zone, DomainName, ResponsePolicy, Zone};
 
 type Error = Box<dyn std::error::Error>;
 type Result<T> = std::result::Result<T, Error>;
 
+// Upper 8 bits of the 12 bit BADVERS (16) response code, carried in the OPT record
+const BADVERS_EXTENDED_RCODE: u8 = 1;
+// DNS over TCP messages are prefixed with a two byte length
+const MAX_TCP_PAYLOAD: usize = u16::MAX as usize;
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+// Each TCP connection has a thread of its own, those past this are closed
+// straight away rather than left to use up threads and file descriptors
+const MAX_TCP_CONNECTIONS: usize = 256;
+const CACHE_EXPIRY_INTERVAL: Duration = Duration::from_secs(30);
+
 #[derive(Debug)]
 struct MessageResult {
     id: String,
-    is_complete: bool
+    // The zone the chunk arrived on
+    zone: DomainName,
+    is_complete: bool,
+    // Where the chunk came from, None for chunks restored from the journal
+    resolver: Option<SocketAddr>,
+    // Set when the chunk clashed with one already received under the same ID
+    conflict: Option<ChunkConflict>,
 }
 
 fn get_unix_epoch_bytes() -> [u8; 8] {
@@ -34,101 +92,390 @@ fn get_checksum(val: &[u8]) -> u8 {
     (c % 255) as u8
 }
 
-fn add_inbound_query(message_buffer_cache: &mut MessageBufferCache,  name: &str, domain: &str) -> Result<MessageResult> {
-    println!("Received query: {:?}", name);
-    let message_chunk = MessageChunk::from(name, domain)?;
+fn add_inbound_query(message_buffer_cache: &mut MessageBufferCache,  message_chunk: MessageChunk, zone: &Zone, resolver: SocketAddr) -> Result<MessageResult> {
     let id = message_chunk.id();
     match message_buffer_cache.add(message_chunk) {
         Ok(is_complete) => {
-            Ok(MessageResult{id, is_complete})
+            Ok(MessageResult{id, zone: zone.apex.clone(), is_complete, resolver: Some(resolver), conflict: None})
         },
-        Err(e) => Err(e)
+        Err(e) => match e.downcast::<ChunkConflict>() {
+            Ok(conflict) => {
+                Ok(MessageResult{id, zone: zone.apex.clone(), is_complete: false, resolver: Some(resolver), conflict: Some(*conflict)})
+            },
+            Err(e) => Err(e)
+        }
     }
 }
 
-fn handle_completed_message(msg: Vec<u8>, output_dir: &str, id: &str) -> Result<()>{
+/// Write every version of a message which had conflicting chunks next to the
+/// regular output, so a collision can be looked into rather than silently lost
+fn handle_conflicting_message(conflict: &ChunkConflict, zone: &Zone, server: &Server) -> Result<()> {
+    eprintln!("[{}] {}, quarantining message", zone.apex, conflict);
+    // With --no-files the log line above is all there is
+    if !server.write_files {
+        return Ok(());
+    }
+    let now: DateTime<Utc> = Utc::now();
+    let filename = format!("{}_{}_{}.conflict.json", now.format("%Y-%m-%d_%H-%M-%S"), zone.apex, conflict.id);
+    let destination = std::path::Path::new(&zone.output_dir).join(filename);
+    let chunks: Vec<_> = conflict.versions.iter()
+        .map(|(idx, versions)| json!({"idx": idx, "versions": versions}))
+        .collect();
+    let report = json!({
+        "id": conflict.id,
+        "zone": zone.apex.to_string(),
+        "idx": conflict.idx,
+        "reason": conflict.reason,
+        "chunks": chunks,
+    });
+    std::fs::write(&destination, serde_json::to_string_pretty(&report)?)
+        .map_err(|e| format!("Unable to write to {}: {}", destination.display(), e))?;
+    Ok(())
+}
+
+/// When and from where a completed message arrived
+struct Arrival {
+    first_chunk_at: SystemTime,
+    last_chunk_at: SystemTime,
+    resolver: Option<SocketAddr>,
+}
+
+fn handle_completed_message(msg: Vec<u8>, zone: &Zone, id: &str, arrival: Arrival, server: &Server) -> Result<()>{
     // Turn into text
     // Parse JSON
     // Extract name of device and write to file
-    //      datetime-id.json
+    //      datetime_zone_id.json
     let now: DateTime<Utc> = Utc::now();
     let now_str = now.format("%Y-%m-%d_%H-%M-%S");
-    let filename = format!("{}_{}", now_str, id);
-    let filepath = std::path::Path::new(output_dir).join(filename);
+    let filename = format!("{}_{}_{}", now_str, zone.apex, id);
+    let filepath = std::path::Path::new(&zone.output_dir).join(filename);
     let filepath_str = filepath.to_str().unwrap();
+    // A scan's version byte and small counts are valid UTF-8, so try the
+    // decoder before taking the message for text
+    let mut scan = None;
+    if msg.first() == Some(&WIFI_SCAN_VERSION) {
+        match WifiScan::decode(&msg) {
+            Ok(decoded) => scan = Some(decoded),
+            Err(e) => eprintln!("[{}] Unable to decode {}: {}", zone.apex, id, e),
+        }
+    }
     match std::str::from_utf8(&msg) {
-        Ok(message_str) => {
-            let destination = format!("{}.txt", filepath_str);
-            std::fs::write(&destination, &message_str).unwrap_or_else(|_|
-                panic!("Unable to write to: {}", &destination));
-            println!("{:}", &message_str);
+        Ok(message_str) if scan.is_none() => {
+            if server.write_files {
+                let destination = format!("{}.txt", filepath_str);
+                std::fs::write(&destination, message_str)
+                    .map_err(|e| format!("Unable to write to {}: {}", destination, e))?;
+            }
+            println!("[{}] {:}", zone.apex, &message_str);
+        }
+        _ => {
+            if server.write_files {
+                let destination = format!("{}.bin", filepath_str);
+                std::fs::write(&destination, &msg)
+                    .map_err(|e| format!("Unable to write to {}: {}", destination, e))?;
+                println!("[{}] Wrote binary content to {}", zone.apex, filepath_str);
+            }
         }
-        Err(_) => {
-            let destination = format!("{}.bin", filepath_str);
-            std::fs::write(&destination, &msg).unwrap_or_else(|_|
-                panic!("Unable to write to: {}", &destination));
-            println!("Wrote binary content to {}", filepath_str);
+    }
+
+    let place = match (&server.places, &scan) {
+        (Some(places), Some(scan)) => places.recognise(scan),
+        _ => None,
+    };
+    if let Some(place) = &place {
+        println!("[{}] Recognised {} ({:.0}% confidence)", zone.apex, place.name, place.confidence * 100.0);
+    }
+    if let Some(scan) = &scan {
+        if server.write_files {
+            let destination = format!("{}.json", filepath_str);
+            let mut decoded = serde_json::to_value(scan)?;
+            if server.places.is_some() {
+                decoded["place"] = serde_json::to_value(&place)?;
+            }
+            std::fs::write(&destination, serde_json::to_string_pretty(&decoded)?)
+                .map_err(|e| format!("Unable to write to {}: {}", destination, e))?;
+        }
+        println!("[{}] Decoded scan of {} networks via '{}' from {}", zone.apex, scan.access_points.len(), scan.ssid, id);
+    }
+
+    let message_row = match &server.storage {
+        Some(storage) => {
+            let stored = StoredMessage {
+                message_id: id,
+                zone: &zone.apex,
+                resolver: arrival.resolver,
+                first_chunk_at: arrival.first_chunk_at,
+                last_chunk_at: arrival.last_chunk_at,
+                raw: &msg,
+                scan: scan.as_ref(),
+                place: place.as_ref(),
+            };
+            let row = storage.lock().unwrap().record_message(&stored)
+                .map_err(|e| format!("Unable to store {}: {}", id, e))?;
+            Some(row)
+        }
+        None => None,
+    };
+
+    let event = (!server.events.is_empty()).then(|| MessageEvent {
+        event: MESSAGE_COMPLETED,
+        device_id: message_handler::device_id_of(id).to_string(),
+        message_id: id.to_string(),
+        zone: zone.apex.to_string(),
+        first_chunk_at: export::format_time(arrival.first_chunk_at),
+        last_chunk_at: export::format_time(arrival.last_chunk_at),
+        payload: event_payload(&msg, scan.as_ref()),
+        place: place.clone(),
+        location: None,
+    });
+    if let Some(event) = &event {
+        send_event(&server.events, event);
+    }
+
+    if let (Some(scan), Some(geolocation)) = (scan, &server.geolocation) {
+        let job = GeolocationJob {
+            zone: zone.apex.clone(),
+            id: id.to_string(),
+            scan,
+            filepath: if server.write_files { Some(filepath_str.to_string()) } else { None },
+            message_row,
+            event,
+        };
+        if geolocation.send(job).is_err() {
+            eprintln!("[{}] Geolocation worker has stopped", zone.apex);
         }
     }
 
     Ok(())
 }
 
+/// What a message event carries: the decoded scan if there is one, as a
+/// scan's bytes can happen to be valid UTF-8 too, otherwise the text
+fn event_payload(msg: &[u8], scan: Option<&WifiScan>) -> serde_json::Value {
+    match (scan, std::str::from_utf8(msg)) {
+        (Some(scan), _) => serde_json::to_value(scan).unwrap_or_default(),
+        (None, Ok(text)) => serde_json::Value::String(text.to_string()),
+        (None, Err(_)) => serde_json::Value::Null,
+    }
+}
+
+/// A decoded scan waiting to be geolocated, `filepath` being where the raw
+/// message was written without its extension and `message_row` where it
+/// was stored, for whichever of the two are enabled. `event` is sent on to
+/// the event workers again with the fix.
+struct GeolocationJob {
+    zone: DomainName,
+    id: String,
+    scan: WifiScan,
+    filepath: Option<String>,
+    message_row: Option<i64>,
+    event: Option<MessageEvent>,
+}
+
+/// Where fixes go besides the log
+struct FixOutputs {
+    storage: Option<Arc<Mutex<Storage>>>,
+    events: Vec<Sender<MessageEvent>>,
+}
+
+/// Write a fix along with the APs left out of it
+fn write_location(destination: &str, location: &Location, filtered: &[FilteredAp]) -> Result<()> {
+    let mut report = serde_json::to_value(location)?;
+    report["filtered"] = serde_json::to_value(filtered)?;
+    std::fs::write(destination, serde_json::to_string_pretty(&report)?)
+        .map_err(|e| format!("Unable to write to {}: {}", destination, e))?;
+    Ok(())
+}
+
+/// Geolocate scans one at a time off the DNS threads, as each lookup is a
+/// round trip to the provider
+fn geolocate_scans(geolocator: Box<dyn Geolocator>, mut ap_filter: ApFilter, outputs: FixOutputs, jobs: Receiver<GeolocationJob>) {
+    for job in jobs {
+        let (scan, filtered) = ap_filter.filter(&job.scan);
+        for filtered_ap in &filtered {
+            println!("[{}] Not geolocating with {}: {:?}", job.zone, filtered_ap.mac_address, filtered_ap.reason);
+        }
+        if scan.access_points.is_empty() {
+            eprintln!("[{}] Unable to geolocate {}: all {} networks were filtered", job.zone, job.id, filtered.len());
+            continue;
+        }
+
+        match geolocator.locate(&scan) {
+            Ok(location) => {
+                for moved in ap_filter.record_fix(&scan, &location) {
+                    println!("[{}] {} has moved, no longer geolocating with it", job.zone, moved);
+                }
+                println!("[{}] Located within {:.0}m: {}", job.zone, location.accuracy, location.maps_url());
+                if let Some(filepath) = &job.filepath {
+                    let destination = format!("{}.location.json", filepath);
+                    if let Err(e) = write_location(&destination, &location, &filtered) {
+                        eprintln!("[{}] {}", job.zone, e);
+                    }
+                }
+                if let (Some(storage), Some(message_row)) = (&outputs.storage, job.message_row) {
+                    if let Err(e) = storage.lock().unwrap().record_location(message_row, &location) {
+                        eprintln!("[{}] Unable to store the location of {}: {}", job.zone, job.id, e);
+                    }
+                }
+                if let Some(event) = job.event {
+                    send_event(&outputs.events, &MessageEvent { event: MESSAGE_LOCATED, location: Some(location), ..event });
+                }
+            }
+            Err(e) => eprintln!("[{}] Unable to geolocate {}: {}", job.zone, job.id, e),
+        }
+    }
+}
+
+/// State shared between the UDP and TCP listeners
+struct Server {
+    message_buffer_cache: Mutex<MessageBufferCache>,
+    // Always locked after the cache, so entries are written in the order
+    // they were applied
+    journal: Option<Mutex<Journal>>,
+    zones: Vec<Zone>,
+    // Recognised in every decoded scan, if enabled
+    places: Option<Places>,
+    // Completed scans are sent here to be geolocated, if enabled
+    geolocation: Option<Sender<GeolocationJob>>,
+    // Completed messages are recorded here, if enabled
+    storage: Option<Arc<Mutex<Storage>>>,
+    // Completed messages and fixes are sent to each of these, for webhooks
+    // and MQTT
+    events: Vec<Sender<MessageEvent>>,
+    // Write completed messages to the output directory
+    write_files: bool,
+    // Served on /metrics by the HTTP API
+    metrics: Arc<Metrics>,
+}
+
+/// Answer a question for a name inside one of our zones, returning the
+/// result of adding the chunk if it carried tunnelled data
+fn answer_in_zone(question: &DnsQuestion, zone: &Zone, packet: &mut DnsPacket, resolver: SocketAddr, server: &Server) -> Option<Result<MessageResult>> {
+    if zone.answer_static(question, packet) {
+        return None;
+    }
+
+    if question.qtype == QueryType::NS || !MessageChunk::has_header(&question.name) {
+        // A resolver walking down towards the full chunk name one label at a
+        // time. Answer NODATA, as NXDOMAIN would tell it that nothing below
+        // exists (RFC 8020) and the chunk would never arrive.
+        zone.answer_nodata(packet);
+        server.metrics.minimised_query();
+        return None;
+    }
+
+    println!("Received query: {:?}", question.name);
+    let message_chunk = match MessageChunk::from(&question.name, &zone.apex) {
+        Ok(message_chunk) => message_chunk,
+        Err(e) => {
+            server.metrics.parse_error(ParseError::MessageChunk);
+            zone.answer_nodata(packet);
+            return Some(Err(e));
+        }
+    };
+    if !zone.allows_device(&message_chunk.device_id()) {
+        packet.header.authoritative_answer = false;
+        packet.header.rescode = ResultCode::REFUSED;
+        return Some(Err(format!("Device {} is not allowed on {}", message_chunk.device_id(), zone.apex).into()));
+    }
+
+    let mut message_buffer_cache = server.message_buffer_cache.lock().unwrap();
+    if let Some(journal) = &server.journal {
+        if let Err(e) = journal.lock().unwrap().record_chunk(&message_chunk) {
+            eprintln!("Unable to journal chunk: {}", e);
+        }
+    }
+    let result = add_inbound_query(&mut message_buffer_cache, message_chunk, zone, resolver);
+    if let (Ok(MessageResult { conflict: Some(conflict), .. }), Some(journal)) = (&result, &server.journal) {
+        let key = MessageKey { zone: zone.apex.clone(), id: conflict.id.clone() };
+        if let Err(e) = journal.lock().unwrap().record_quarantined(&key) {
+            eprintln!("Unable to journal quarantined message: {}", e);
+        }
+    }
+    server.metrics.set_cache_occupancy(message_buffer_cache.len(), message_buffer_cache.memory_used());
+    drop(message_buffer_cache);
+
+    let time_bytes = get_unix_epoch_bytes();
+    let time_checksum = get_checksum(&time_bytes[0..5]);
 
-/// Handle a single incoming packet
-fn handle_query(socket: &UdpSocket, message_buffer_cache: &mut MessageBufferCache, domain: &str) -> Result<MessageResult> {
-    // With a socket ready, we can go ahead and read a packet. This will
-    // block until one is received.
-    let mut req_buffer = BytePacketBuffer::new();
+    // Devices only ever look up A records, the address tells them whether
+    // the message is complete. Anything else a resolver asks along the
+    // way exists but has no data.
+    packet.header.rescode = ResultCode::NOERROR;
+    if question.qtype != QueryType::A || zone.response_policy == ResponsePolicy::NoData {
+        zone.answer_nodata(packet);
+    } else if matches!(result, Ok(MessageResult { is_complete: true, .. })) {
+        packet.answers.push(DnsRecord::A{
+            domain: question.name.clone(),
+            ttl: 255,
+            addr: Ipv4Addr::new(11,time_bytes[3],time_bytes[4],time_checksum)
+        });
+    } else {
+        packet.answers.push(DnsRecord::A{
+            domain: question.name.clone(),
+            ttl: 255,
+            addr: Ipv4Addr::new(10, time_bytes[0],time_bytes[1],time_bytes[2])
+        });
+    }
+    Some(result)
+}
 
-    // The `recv_from` function will write the data into the provided buffer,
-    // and return the length of the data read as well as the source address.
-    // We're not interested in the length, but we need to keep track of the
-    // source in order to send our reply later on.
-    let (_, src) = socket.recv_from(&mut req_buffer.buf)?;
+/// Parse a single incoming packet, feed its question into the message cache
+/// and return the encoded reply along with the result of adding the chunk,
+/// if the query carried one
+fn handle_query(raw: &[u8], transport: Transport, resolver: SocketAddr, server: &Server) -> Result<(Vec<u8>, Option<Result<MessageResult>>)> {
+    server.metrics.query_received(transport);
+    let mut req_buffer = BytePacketBuffer::from_bytes(raw);
 
     // Next, `DnsPacket::from_buffer` is used to parse the raw bytes into
     // a `DnsPacket`.
-    let mut request = DnsPacket::from_buffer(&mut req_buffer)?;
-
+    let mut request = match DnsPacket::from_buffer(&mut req_buffer) {
+        Ok(request) => request,
+        Err(e) => {
+            server.metrics.parse_error(ParseError::DnsPacket);
+            return Ok((format_error(raw)?, Some(Err(e))));
+        }
+    };
 
-    // Create and initialize the response packet
+    // Create and initialize the response packet. We are the authority for our
+    // zone and don't offer recursion to anyone.
     let mut packet = DnsPacket::new();
     packet.header.id = request.header.id;
-    packet.header.recursion_desired = true;
-    packet.header.recursion_available = true;
+    packet.header.recursion_desired = request.header.recursion_desired;
+    packet.header.recursion_available = false;
     packet.header.response = true;
     packet.header.authoritative_answer = false;
 
-    let question = request.questions.pop();
-
-    let mut result: Result<MessageResult> = Err("Nothing in dns query found to process".to_string().into());
-    if let Some(question) = question {
-
-        result = add_inbound_query(message_buffer_cache, &question.name, domain);
+    // Echo EDNS0 back to senders which use it, advertising our own payload size.
+    // We only speak version 0, anything else gets BADVERS (RFC 6891 6.1.3)
+    // and isn't answered, so its chunk never reaches the cache
+    let edns_version = request.edns_version();
+    let bad_version = matches!(edns_version, Some(version) if version != 0);
+    if let Some(version) = edns_version {
+        let extended_rcode = if version == 0 { 0 } else { BADVERS_EXTENDED_RCODE };
+        packet.set_edns(MAX_UDP_PAYLOAD as u16, extended_rcode);
+    }
 
-        let time_bytes = get_unix_epoch_bytes();
-        let time_checksum = get_checksum(&time_bytes[0..5]);
+    let question = request.questions.pop();
 
-        // Since all is set up and as expected, the query can be forwarded to the
-        // target server. There's always the possibility that the query will
-        // fail, in which case the `SERVFAIL` response code is set to indicate
-        // as much to the client. If rather everything goes as planned, the
-        // question and response records as copied into our response packet.
+    let mut result: Option<Result<MessageResult>> = Some(Err("Nothing in dns query found to process".to_string().into()));
+    if bad_version {
+        packet.questions.extend(question);
+        result = None;
+    } else if let Some(question) = question {
         packet.questions.push(question.clone());
-        packet.header.rescode = ResultCode::NOERROR;
-        if result.is_ok() && result.as_ref().unwrap().is_complete {
-            packet.answers.push(DnsRecord::A{
-                domain: question.name.clone(),
-                ttl: 255,
-                addr: Ipv4Addr::new(11,time_bytes[3],time_bytes[4],time_checksum)
-            });
-        } else {
-            packet.answers.push(DnsRecord::A{
-                domain: question.name.clone(),
-                ttl: 255,
-                addr: Ipv4Addr::new(10, time_bytes[0],time_bytes[1],time_bytes[2])
-            });
+
+        match find_zone(&server.zones, &DomainName::new(&question.name)) {
+            Some(zone) => {
+                packet.header.authoritative_answer = true;
+                result = answer_in_zone(&question, zone, &mut packet, resolver, server);
+            }
+            None => {
+                // Not ours, and we don't recurse
+                packet.header.rescode = ResultCode::REFUSED;
+                println!("Refused query outside of zones: {:?}", question.name);
+                result = None;
+            }
         }
     } // Being mindful of how unreliable input data from arbitrary senders can be, we
     // need make sure that a question is actually present. If not, we return `FORMERR`
@@ -137,14 +484,229 @@ fn handle_query(socket: &UdpSocket, message_buffer_cache: &mut MessageBufferCach
         packet.header.rescode = ResultCode::FORMERR;
     }
 
-    // The only thing remaining is to encode our response and send it off!
-    let mut res_buffer = BytePacketBuffer::new();
-    packet.write(&mut res_buffer)?;
+    // The only thing remaining is to encode our response. Over UDP we have to
+    // respect the payload size the requester can handle, over TCP the two byte
+    // length prefix is the only limit.
+    let max_size = match transport {
+        Transport::Udp => request.max_response_size(),
+        Transport::Tcp => MAX_TCP_PAYLOAD,
+    };
+    let mut res_buffer = BytePacketBuffer::with_limit(max_size);
+    if let Err(e) = packet.write(&mut res_buffer) {
+        if !e.is::<BufferFull>() {
+            return Err(e);
+        }
+        // Too large for UDP, send what fits with the TC bit set so the
+        // resolver retries over TCP (RFC 7766)
+        packet.truncate();
+        res_buffer = BytePacketBuffer::with_limit(max_size);
+        packet.write(&mut res_buffer)?;
+    }
+
+    let data = res_buffer.get_data()?.to_vec();
+    Ok((data, result))
+}
+
+/// FORMERR for a query which couldn't be parsed, with its ID if it got that far
+fn format_error(raw: &[u8]) -> Result<Vec<u8>> {
+    let mut packet = DnsPacket::new();
+    if let [high, low, ..] = *raw {
+        packet.header.id = u16::from_be_bytes([high, low]);
+    }
+    packet.header.response = true;
+    packet.header.rescode = ResultCode::FORMERR;
+    let mut buffer = BytePacketBuffer::with_limit(DEFAULT_UDP_PAYLOAD);
+    packet.write(&mut buffer)?;
+    Ok(buffer.get_data()?.to_vec())
+}
+
+/// Write out a message once its last chunk has arrived
+fn handle_message_result(result: Result<MessageResult>, server: &Server) {
+    match result {
+        Ok(message_result) => {
+            if let Some(conflict) = &message_result.conflict {
+                let zone = server.zones.iter().find(|zone| zone.apex == message_result.zone);
+                if let Some(zone) = zone {
+                    if let Err(e) = handle_conflicting_message(conflict, zone, server) {
+                        println!("Error {:?}", e);
+                    }
+                }
+            }
+            if message_result.is_complete {
+                // Only the first chunk to complete a message gets it, retransmissions
+                // are acknowledged but not written out again
+                let key = MessageKey { zone: message_result.zone.clone(), id: message_result.id.clone() };
+                let mut message_buffer_cache = server.message_buffer_cache.lock().unwrap();
+                let arrival = message_buffer_cache.received_between(&key)
+                    .map(|(first_chunk_at, last_chunk_at)| Arrival { first_chunk_at, last_chunk_at, resolver: message_result.resolver });
+                let chunks = message_buffer_cache.chunks_received(&key);
+                let message = message_buffer_cache.take_completed(&key);
+                drop(message_buffer_cache);
+                let zone = server.zones.iter().find(|zone| zone.apex == message_result.zone);
+                if let (Some(message), Some(zone), Some(arrival)) = (message, zone, arrival) {
+                    let reassembly = arrival.last_chunk_at.duration_since(arrival.first_chunk_at).unwrap_or_default();
+                    let delivered = handle_completed_message(message, zone, &message_result.id, arrival, server);
+                    // Only journaled as completed once written out, until then a
+                    // restart delivers it again from its journaled chunks
+                    let mut message_buffer_cache = server.message_buffer_cache.lock().unwrap();
+                    match delivered {
+                        Ok(()) => {
+                            // Counted once delivered, not for attempts which fail and
+                            // are retried
+                            if let Some(chunks) = chunks {
+                                server.metrics.message_completed(chunks, reassembly);
+                            }
+                            message_buffer_cache.confirm_delivered(&key);
+                            if let Some(journal) = &server.journal {
+                                if let Err(e) = journal.lock().unwrap().record_completed(&key) {
+                                    eprintln!("Unable to journal completed message: {}", e);
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            println!("Error {:?}", e);
+                            message_buffer_cache.release(&key);
+                        }
+                    }
+                    server.metrics.set_cache_occupancy(message_buffer_cache.len(), message_buffer_cache.memory_used());
+                }
+            }
+        }
+        Err(e) => eprintln!("An error occurred: {}", e),
+    }
+}
+
+fn serve_udp(socket: UdpSocket, server: Arc<Server>) {
+    // EDNS0 capable senders may send us more than the classic 512 bytes,
+    // so leave room for the largest we accept.
+    let mut raw = [0; MAX_UDP_PAYLOAD];
+    loop {
+        // The `recv_from` function will write the data into the provided buffer,
+        // and return the length of the data read as well as the source address.
+        // We need to keep track of the source in order to send our reply later on.
+        let (len, src) = match socket.recv_from(&mut raw) {
+            Ok(received) => received,
+            Err(e) => {
+                eprintln!("An error occurred: {}", e);
+                continue;
+            }
+        };
+        match handle_query(&raw[..len], Transport::Udp, src, &server) {
+            Ok((data, result)) => {
+                if let Err(e) = socket.send_to(&data, src) {
+                    eprintln!("An error occurred: {}", e);
+                }
+                if let Some(result) = result {
+                    handle_message_result(result, &server);
+                }
+            }
+            Err(e) => eprintln!("An error occurred: {}", e),
+        }
+    }
+}
+
+/// Read one length-prefixed message (RFC 1035 4.2.2), None once the peer
+/// closes the connection or leaves it idle between messages
+fn read_tcp_message(stream: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut len_bytes = [0; 2];
+    match stream.read_exact(&mut len_bytes) {
+        Ok(()) => {}
+        // Read timeouts show up as either, depending on the platform
+        Err(e) if matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut raw = vec![0; u16::from_be_bytes(len_bytes) as usize];
+    stream.read_exact(&mut raw)?;
+    Ok(Some(raw))
+}
+
+fn write_tcp_message(stream: &mut impl Write, data: &[u8]) -> Result<()> {
+    let mut framed = Vec::with_capacity(data.len() + 2);
+    framed.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    framed.extend_from_slice(data);
+    stream.write_all(&framed)?;
+    Ok(())
+}
+
+/// Answer length-prefixed queries on a TCP connection until the peer
+/// closes it or goes idle (RFC 1035 4.2.2, RFC 7766)
+fn handle_tcp_connection(mut stream: TcpStream, server: &Server) -> Result<()> {
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    let peer = stream.peer_addr()?;
+    while let Some(raw) = read_tcp_message(&mut stream)? {
+        let (data, result) = handle_query(&raw, Transport::Tcp, peer, server)?;
+        write_tcp_message(&mut stream, &data)?;
+        if let Some(result) = result {
+            handle_message_result(result, server);
+        }
+    }
+    Ok(())
+}
+
+// Held by a connection's thread, giving its place up when the thread ends
+struct TcpConnectionSlot(Arc<AtomicUsize>);
 
-    let data = res_buffer.get_data()?;
+impl Drop for TcpConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
-    socket.send_to(data, src)?;
-    result
+fn serve_tcp(listener: TcpListener, server: Arc<Server>, max_connections: usize) {
+    let open_connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                // Dropping the stream closes it, resolvers fall back to
+                // another server or retry later
+                if open_connections.fetch_add(1, Ordering::SeqCst) >= max_connections {
+                    open_connections.fetch_sub(1, Ordering::SeqCst);
+                    continue;
+                }
+                let slot = TcpConnectionSlot(Arc::clone(&open_connections));
+                let server = Arc::clone(&server);
+                thread::spawn(move || {
+                    let _slot = slot;
+                    if let Err(e) = handle_tcp_connection(stream, &server) {
+                        eprintln!("An error occurred: {}", e);
+                    }
+                });
+            }
+            Err(e) => eprintln!("An error occurred: {}", e),
+        }
+    }
+}
+
+fn enrol_place(places_path: &str, name: &str, report: &str) -> Result<()> {
+    let data = std::fs::read(report).map_err(|e| format!("Unable to read {}: {}", report, e))?;
+    let scan = WifiScan::decode(&data).map_err(|e| format!("Unable to decode {}: {}", report, e))?;
+    let places_path = std::path::Path::new(places_path);
+    let mut places = Places::load(places_path)?;
+    places.enrol(name, &scan);
+    places.save(places_path)?;
+    println!("Enrolled {} networks from {} as {}", scan.access_points.len(), report, name);
+    Ok(())
+}
+
+fn export_fixes(matches: &clap::ArgMatches) -> Result<()> {
+    let format = matches.value_of("format").unwrap();
+    let format = ExportFormat::parse(format).ok_or_else(|| format!("Unknown export format {}", format))?;
+    let device_id = matches.value_of("DEVICE").unwrap();
+    let storage = Storage::open_read_only(std::path::Path::new(matches.value_of("database").unwrap()))?;
+    let fixes = storage.fixes(
+        device_id,
+        matches.value_of("from").map(export::parse_time).transpose()?,
+        matches.value_of("to").map(export::parse_time).transpose()?,
+    )?;
+    let exported = export::export(format, device_id, &fixes)?;
+    match matches.value_of("out") {
+        Some(path) => {
+            std::fs::write(path, exported).map_err(|e| format!("Unable to write to {}: {}", path, e))?;
+            println!("Exported {} fixes of {} to {}", fixes.len(), device_id, path);
+        }
+        None => print!("{}", exported),
+    }
+    Ok(())
 }
 
 fn main() -> Result<()> {
@@ -156,31 +718,425 @@ fn main() -> Result<()> {
                           .args_from_usage(
                               "-p, --port=[PORT]        'Port to use, default 53'
                               -o, --out=[PORT]          'Output directory to save locations'
-                              <DOMAIN>                  'Root domain'
+                              -c, --config=[FILE]       'JSON file listing the zones to serve, see zones_sample.json'
+                              --cache-size=[COUNT]      'Partial messages to hold at once, default 64'
+                              --message-ttl=[SECONDS]   'Drop partial messages after this long without a chunk, default 600'
+                              --cache-memory=[BYTES]    'Memory budget for partial messages, default 16MiB'
+                              -j, --journal=[FILE]      'Keep partial messages in this file so they survive a restart'
+                              -d, --database=[FILE]     'SQLite database to record completed messages and their fixes in'
+                              --no-files                'Only record completed messages in --database, not the output directory'
+                              --webhook=[URL]...        'POST an event to this URL for every completed message and fix, may be repeated'
+                              --webhook-secret=[SECRET] 'Sign webhook bodies with HMAC-SHA256 in the X-Dns-Drop-Signature header'
+                              --webhook-retries=[COUNT] 'Attempts at delivering a webhook event before giving up, default 12'
+                              --webhook-backoff=[SECONDS] 'Wait before retrying a webhook, doubling each time up to an hour, default 10'
+                              --webhook-queue=[FILE]    'Where undelivered webhook events wait to be retried, default webhook_queue.json in the output directory'
+                              --mqtt=[ADDRESS]          'MQTT broker to publish messages and fixes to, such as localhost:1883'
+                              --mqtt-qos=[QOS]          'QoS to publish with, 0, 1 or 2, default 1'
+                              --mqtt-client-id=[ID]     'Client identifier to connect with, default dns_drop'
+                              --mqtt-username=[NAME]    'User name to connect with, along with --mqtt-password'
+                              --mqtt-password=[PASSWORD] 'Password to connect with'
+                              --mqtt-discovery=[PREFIX] 'Home Assistant discovery prefix devices are announced under, default homeassistant'
+                              --no-mqtt-discovery       'Do not announce devices to Home Assistant'
+                              --http=[ADDRESS]          'Serve Prometheus metrics and a JSON API of what is in --database on this address, such as 127.0.0.1:8080'
+                              --geolocate-key=[KEY]     'Google API key, geolocates every scan received'
+                              --geolocate-url=[URL]     'Base URL of a Google compatible geolocation API, default https://www.googleapis.com'
+                              --ap-database=[FILE]      'CSV of known access points, geolocates every scan offline'
+                              --path-loss=[FILE]        'Per band path loss calibration for --ap-database, see path_loss_sample.json'
+                              --geo-cache=[FILE]        'Reuse fixes for scans much like earlier ones, kept in this file'
+                              --geo-cache-similarity=[RATIO] 'Share of the strongest APs two scans need in common to share a fix, default 0.6'
+                              --hotspot-ouis=[FILE]     'Mobile hotspot OUIs to ignore when geolocating, on top of hotspot_ouis.txt'
+                              --places=[FILE]           'Places enrolled with the enrol command, recognised in every scan'
+                              --place-threshold=[RATIO] 'Least similarity to an enrolled scan to recognise a place, default 0.4'
+                              -n, --nameservers=[HOSTS] 'Comma separated nameservers for the zones, default ns1.DOMAIN'
+                              -a, --address=[IP]        'Public IPv4 address of this server, served as nameserver glue'
+                              --hostmaster=[NAME]       'SOA responsible mailbox, default hostmaster.DOMAIN'
+                              [DOMAIN]...               'Root domains to serve'
                               -v...                     'Sets the level of verbosity'")
+                          .subcommand(SubCommand::with_name("enrol")
+                              .about("Enrol a saved scan report as a reference for a named place")
+                              .args_from_usage(
+                                  "--places=<FILE>    'Places file to add to, created if missing'
+                                  <NAME>              'Name of the place, enrolling an existing one adds another reference'
+                                  <REPORT>            'Scan report the server saved, a .bin file'"))
+                          .subcommand(SubCommand::with_name("export")
+                              .about("Export the fixes of a device recorded in --database")
+                              .args_from_usage(
+                                  "-d, --database=<FILE> 'SQLite database the server recorded messages in'
+                                  -f, --format=<FORMAT>  'geojson, gpx, kml or csv'
+                                  --from=[TIME]          'Only fixes received at or after this RFC 3339 time'
+                                  --to=[TIME]            'Only fixes received at or before this RFC 3339 time'
+                                  -o, --out=[FILE]       'File to write to, default standard output'
+                                  <DEVICE>               'Device ID, the first 8 characters of its message IDs'"))
                           .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("enrol") {
+        return enrol_place(matches.value_of("places").unwrap(), matches.value_of("NAME").unwrap(), matches.value_of("REPORT").unwrap());
+    }
+    if let Some(matches) = matches.subcommand_matches("export") {
+        return export_fixes(matches);
+    }
+
     let port: u16 = matches.value_of("port").unwrap_or("53").parse().unwrap();
-    let domain = matches.value_of("DOMAIN").unwrap();
     let output_dir: &str = matches.value_of("out").unwrap_or("");
-    // Bind an UDP socket on port 2053
+
+    let defaults = ZoneDefaults {
+        nameservers: matches.value_of("nameservers").map(|nameservers| nameservers.split(',').map(DomainName::new).collect()),
+        address: matches.value_of("address").map(|address| address.parse()).transpose()?,
+        hostmaster: matches.value_of("hostmaster").map(DomainName::new),
+        output_dir: output_dir.to_string(),
+        response_policy: ResponsePolicy::Ack,
+    };
+    let mut zones = match matches.value_of("config") {
+        Some(path) => load_zones(path, &defaults)?,
+        None => Vec::new(),
+    };
+    for domain in matches.values_of("DOMAIN").into_iter().flatten() {
+        let zone = defaults.zone(domain);
+        if zone.apex.labels().is_empty() {
+            return Err("DOMAIN can't be empty, it would match every name".into());
+        }
+        if zones.iter().any(|other| other.apex == zone.apex) {
+            return Err(format!("Zone {} is given more than once", zone.apex).into());
+        }
+        zones.push(zone);
+    }
+    if zones.is_empty() {
+        return Err("No zones to serve, pass a DOMAIN or --config".into());
+    }
+    // Bind UDP and TCP sockets on the same port, resolvers fall back to TCP
+    // when they see a truncated UDP answer
     let socket = UdpSocket::bind(("0.0.0.0", port))?;
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
 
-    // For now, queries are handled sequentially, so an infinite loop for servicing
-    // requests is initiated.
-    let mut message_buffer_cache: MessageBufferCache = MessageBufferCache::new(64);
-    loop {
-        match handle_query(&socket, &mut message_buffer_cache, domain) {
-            Ok(message_result) => {
-                if message_result.is_complete {
-                    if let Some(message) = message_buffer_cache.get_value(&message_result.id) {
-                        if let Err(e) = handle_completed_message(message, output_dir, &message_result.id) {
-                            println!("Error {:?}", e);
-                        }
-                    }
+    let metrics = Arc::new(Metrics::new());
+    let cache_size: usize = matches.value_of("cache-size").unwrap_or("64").parse()?;
+    let evict_metrics = Arc::clone(&metrics);
+    let mut message_buffer_cache = MessageBufferCache::new(cache_size)
+        .on_evict(Box::new(move |evicted| {
+            evict_metrics.message_evicted(evicted.reason);
+            if evicted.complete {
+                eprintln!("[{}] Dropped message {} ({:?}) after {}s, it completed but was never written out",
+                    evicted.zone, evicted.id, evicted.reason, evicted.age.as_secs());
+            } else {
+                eprintln!("[{}] Dropped incomplete message {} ({:?}) after {}s, received chunks {:?}, missing {:?}{}",
+                    evicted.zone, evicted.id, evicted.reason, evicted.age.as_secs(), evicted.received, evicted.missing,
+                    if evicted.last_received { "" } else { " and the last chunk" });
+            }
+        }));
+    if let Some(message_ttl) = matches.value_of("message-ttl") {
+        message_buffer_cache = message_buffer_cache.with_message_ttl(Duration::from_secs(message_ttl.parse()?));
+    }
+    if let Some(cache_memory) = matches.value_of("cache-memory") {
+        message_buffer_cache = message_buffer_cache.with_memory_budget(cache_memory.parse()?);
+    }
+
+    let journal = match matches.value_of("journal") {
+        Some(path) => {
+            let (mut journal, entries) = Journal::open(std::path::Path::new(path))?;
+            let apexes: Vec<DomainName> = zones.iter().map(|zone| zone.apex.clone()).collect();
+            let restored = journal::replay(&mut message_buffer_cache, entries, &apexes);
+            println!("Restored {} chunks from {}", restored, path);
+            // Starts the journal afresh, which also drops a line cut short by a crash
+            journal.compact(&message_buffer_cache)?;
+            Some(Mutex::new(journal))
+        }
+        None => None,
+    };
+    let pending = message_buffer_cache.pending_completed();
+    metrics.set_cache_occupancy(message_buffer_cache.len(), message_buffer_cache.memory_used());
+
+    let storage = match matches.value_of("database") {
+        Some(path) => {
+            let storage = Storage::open(std::path::Path::new(path))?;
+            println!("Recording messages in {} (schema version {})", path, storage.schema_version()?);
+            Some(Arc::new(Mutex::new(storage)))
+        }
+        None if matches.is_present("no-files") => return Err("--no-files needs a --database to record messages in".into()),
+        None => None,
+    };
+    let webhooks = matches.values_of("webhook").map(|urls| {
+        let secret = matches.value_of("webhook-secret").map(str::to_string);
+        urls.map(|url| Webhook { url: url.to_string(), secret: secret.clone() }).collect::<Vec<_>>()
+    });
+    let mut events = Vec::new();
+    if let Some(webhooks) = webhooks {
+        let queue_path = match matches.value_of("webhook-queue") {
+            Some(path) => std::path::PathBuf::from(path),
+            None => std::path::Path::new(output_dir).join("webhook_queue.json"),
+        };
+        let mut queue = WebhookQueue::new(webhooks).persist_to(&queue_path)?;
+        if let Some(retries) = matches.value_of("webhook-retries") {
+            queue = queue.with_max_attempts(retries.parse()?);
+        }
+        if let Some(backoff) = matches.value_of("webhook-backoff") {
+            queue = queue.with_backoff(Duration::from_secs(backoff.parse()?));
+        }
+        println!("Sending webhooks, {} undelivered events in {}", queue.len(), queue_path.display());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || webhooks::deliver_webhooks(queue, receiver));
+        events.push(sender);
+    }
+    if let Some(address) = matches.value_of("mqtt") {
+        let mut publisher = MqttPublisher::new(address);
+        if let Some(qos) = matches.value_of("mqtt-qos") {
+            publisher = publisher.with_qos(QoS::parse(qos).ok_or_else(|| format!("Invalid MQTT QoS {}, expected 0, 1 or 2", qos))?);
+        }
+        if let Some(client_id) = matches.value_of("mqtt-client-id") {
+            publisher = publisher.with_client_id(client_id);
+        }
+        match (matches.value_of("mqtt-username"), matches.value_of("mqtt-password")) {
+            (Some(username), Some(password)) => publisher = publisher.with_credentials(username, password),
+            (None, None) => {}
+            _ => return Err("--mqtt-username and --mqtt-password go together".into()),
+        }
+        if matches.is_present("no-mqtt-discovery") {
+            publisher = publisher.with_discovery_prefix(None);
+        } else if let Some(discovery_prefix) = matches.value_of("mqtt-discovery") {
+            publisher = publisher.with_discovery_prefix(Some(discovery_prefix));
+        }
+        println!("Publishing to MQTT broker {}", address);
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || mqtt::publish_events(publisher, receiver));
+        events.push(sender);
+    }
+    let http_api = match matches.value_of("http") {
+        Some(address) => {
+            let listener = TcpListener::bind(address).map_err(|e| format!("Unable to listen on {}: {}", address, e))?;
+            println!("Serving the HTTP API on {}", address);
+            // A connection of its own, so queries never hold up messages being recorded
+            let storage = matches.value_of("database").map(|path| Storage::open(std::path::Path::new(path))).transpose()?;
+            Some((listener, storage))
+        }
+        None => None,
+    };
+
+    // Either Google option turns geolocation on, a compatible service may not
+    // need a key. The offline database is for not sending scans anywhere, so
+    // it can't be combined with them.
+    let geolocate_key = matches.value_of("geolocate-key");
+    let geolocate_url = matches.value_of("geolocate-url");
+    let geolocator: Option<Box<dyn Geolocator>> = match matches.value_of("ap-database") {
+        Some(_) if geolocate_key.is_some() || geolocate_url.is_some() => {
+            return Err("--ap-database can't be combined with --geolocate-key or --geolocate-url".into());
+        }
+        None if matches.is_present("path-loss") => {
+            return Err("--path-loss calibrates --ap-database, which isn't set".into());
+        }
+        Some(path) => {
+            let ap_database = ApDatabase::load(path)?;
+            println!("Loaded {} access points from {}", ap_database.len(), path);
+            let calibration = match matches.value_of("path-loss") {
+                Some(path) => Calibration::load(path)?,
+                None => Calibration::default(),
+            };
+            Some(Box::new(Trilaterator::new(ap_database, calibration)))
+        }
+        None if geolocate_key.is_some() || geolocate_url.is_some() => {
+            Some(Box::new(GoogleGeolocator::new(geolocate_url.unwrap_or(GOOGLE_API_URL), geolocate_key.map(str::to_string))))
+        }
+        None => None,
+    };
+    let mut geo_cache = None;
+    let geolocator = match (geolocator, matches.value_of("geo-cache")) {
+        (Some(geolocator), Some(path)) => {
+            let mut cache = GeoCache::new().persist_to(std::path::Path::new(path))?;
+            if let Some(similarity) = matches.value_of("geo-cache-similarity") {
+                cache = cache.with_similarity(similarity.parse()?)?;
+            }
+            println!("Loaded {} cached fixes from {}", cache.stats().entries, path);
+            let cache = Arc::new(Mutex::new(cache));
+            geo_cache = Some(Arc::clone(&cache));
+            let geolocator: Box<dyn Geolocator> = Box::new(CachedGeolocator::new(geolocator, cache));
+            Some(geolocator)
+        }
+        (geolocator, _) => geolocator,
+    };
+    let mut ap_filter = ApFilter::new();
+    if let Some(path) = matches.value_of("hotspot-ouis") {
+        ap_filter.load_hotspot_ouis(path)?;
+    }
+    let geolocation = geolocator.map(|geolocator| {
+        let (sender, receiver) = mpsc::channel();
+        let outputs = FixOutputs { storage: storage.clone(), events: events.clone() };
+        thread::spawn(move || geolocate_scans(geolocator, ap_filter, outputs, receiver));
+        sender
+    });
+
+    let places = match matches.value_of("places") {
+        Some(path) => {
+            let mut places = Places::load(std::path::Path::new(path))?;
+            if let Some(threshold) = matches.value_of("place-threshold") {
+                places = places.with_threshold(threshold.parse()?)?;
+            }
+            println!("Loaded {} places from {}", places.len(), path);
+            Some(places)
+        }
+        None => None,
+    };
+
+    let server = Arc::new(Server {
+        message_buffer_cache: Mutex::new(message_buffer_cache),
+        journal,
+        zones,
+        places,
+        geolocation,
+        storage,
+        events,
+        write_files: !matches.is_present("no-files"),
+        metrics: Arc::clone(&metrics),
+    });
+
+    // Messages completed just before a restart which never got written out
+    for MessageKey { zone, id } in pending {
+        handle_message_result(Ok(MessageResult { id, zone, is_complete: true, resolver: None, conflict: None }), &server);
+    }
+
+    // TCP connections each get their own thread, UDP queries are handled
+    // sequentially on the main thread
+    let tcp_server = Arc::clone(&server);
+    thread::spawn(move || serve_tcp(listener, tcp_server, MAX_TCP_CONNECTIONS));
+    if let Some((listener, storage)) = http_api {
+        let api = Arc::new(Api { storage: storage.map(Mutex::new), metrics, geo_cache });
+        thread::spawn(move || http_api::serve(listener, api));
+    }
+    // Partial messages otherwise only expire when the next chunk arrives
+    let expiry_server = Arc::clone(&server);
+    thread::spawn(move || loop {
+        thread::sleep(CACHE_EXPIRY_INTERVAL);
+        let mut message_buffer_cache = expiry_server.message_buffer_cache.lock().unwrap();
+        message_buffer_cache.expire();
+        expiry_server.metrics.set_cache_occupancy(message_buffer_cache.len(), message_buffer_cache.memory_used());
+        if let Some(journal) = &expiry_server.journal {
+            let mut journal = journal.lock().unwrap();
+            if journal.needs_compaction() {
+                if let Err(e) = journal.compact(&message_buffer_cache) {
+                    eprintln!("Unable to compact journal: {}", e);
                 }
             }
-            Err(e) => eprintln!("An error occurred: {}", e),
         }
+    });
+    serve_udp(socket, server);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::test_util::TempPath;
+
+    #[test]
+    fn test_tcp_framing() -> Result<()> {
+        let mut stream = Vec::new();
+        write_tcp_message(&mut stream, b"first")?;
+        write_tcp_message(&mut stream, &[7; 300])?;
+        assert_eq!(&stream[..7], b"\x00\x05first");
+        assert_eq!(&stream[7..9], &[1, 44]);
+
+        let mut reader = Cursor::new(stream.clone());
+        assert_eq!(read_tcp_message(&mut reader)?, Some(b"first".to_vec()));
+        assert_eq!(read_tcp_message(&mut reader)?, Some(vec![7; 300]));
+        // The peer closing between messages is the end of the connection,
+        // part way through one is an error
+        assert_eq!(read_tcp_message(&mut reader)?, None);
+        assert!(read_tcp_message(&mut Cursor::new(&stream[7..20])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_error() -> Result<()> {
+        let data = format_error(&[0x12, 0x34, 0xff])?;
+        let packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&data))?;
+        assert_eq!((packet.header.id, packet.header.response, packet.header.rescode), (0x1234, true, ResultCode::FORMERR));
+        Ok(())
+    }
+
+    #[test]
+    fn test_event_payload() -> Result<()> {
+        // Every byte ASCII, so it's valid UTF-8 as well as a scan
+        let msg = b"\x00\x01ABCDEF\x06\x3fhome";
+        let scan = WifiScan::decode(msg)?;
+        assert!(std::str::from_utf8(msg).is_ok());
+        let payload = event_payload(msg, Some(&scan));
+        assert_eq!((payload["ssid"].as_str(), payload["wifiAccessPoints"][0]["macAddress"].as_str()), (Some("home"), Some("41:42:43:44:45:46")));
+
+        assert_eq!(event_payload(b"{\"foo\":1}", None), json!("{\"foo\":1}"));
+        assert_eq!(event_payload(&[0xff, 0xfe], None), serde_json::Value::Null);
+        Ok(())
+    }
+
+    fn server() -> Server {
+        Server {
+            message_buffer_cache: Mutex::new(MessageBufferCache::new(3)),
+            journal: None,
+            zones: vec![Zone::new("foo.co")],
+            places: None,
+            geolocation: None,
+            storage: None,
+            events: Vec::new(),
+            write_files: false,
+            metrics: Arc::new(Metrics::new()),
+        }
+    }
+
+    #[test]
+    fn test_tcp_connection_limit() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        thread::spawn(move || serve_tcp(listener, Arc::new(server()), 1));
+
+        let mut first = TcpStream::connect(address)?;
+        // Over the limit, closed without a word
+        let mut second = TcpStream::connect(address)?;
+        assert_eq!(second.read(&mut [0; 2])?, 0);
+
+        // The first is served as usual, and its place freed once it closes
+        write_tcp_message(&mut first, &format_error(&[0x12, 0x34])?)?;
+        assert!(read_tcp_message(&mut first)?.is_some());
+        drop(first);
+        thread::sleep(Duration::from_millis(100));
+        let mut third = TcpStream::connect(address)?;
+        write_tcp_message(&mut third, &format_error(&[0x12, 0x34])?)?;
+        assert!(read_tcp_message(&mut third)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict_without_files() -> Result<()> {
+        let output_dir = TempPath::new("conflicts");
+        std::fs::create_dir(&output_dir)?;
+        let mut zone = Zone::new("foo.co");
+        zone.output_dir = output_dir.display().to_string();
+        let conflict = ChunkConflict { id: "DDDDDDDDDDDDD".to_string(), idx: 0, reason: "differs".to_string(), versions: Vec::new() };
+        handle_conflicting_message(&conflict, &zone, &server())?;
+        assert_eq!(std::fs::read_dir(&output_dir)?.count(), 0);
+        std::fs::remove_dir(&output_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_version() -> Result<()> {
+        let server = server();
+        let query = |edns_version: u32| -> Result<DnsPacket> {
+            let mut request = DnsPacket::new();
+            request.header.id = 0x1234;
+            request.questions.push(DnsQuestion::new("AADDDDDDDDDDDDDDPMRGM33PEI5.foo.co".to_string(), QueryType::A));
+            request.resources.push(DnsRecord::OPT { packet_len: 1232, flags: edns_version << 16, data: Vec::new() });
+            let mut buffer = BytePacketBuffer::with_limit(MAX_UDP_PAYLOAD);
+            request.write(&mut buffer)?;
+            let (response, _) = handle_query(buffer.get_data()?, Transport::Udp, "127.0.0.1:5353".parse()?, &server)?;
+            DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&response))
+        };
+
+        // Refused with BADVERS and the chunk left alone
+        let response = query(1)?;
+        assert!(response.answers.is_empty());
+        assert!(matches!(response.resources[..], [DnsRecord::OPT { flags, .. }] if flags >> 24 == BADVERS_EXTENDED_RCODE as u32));
+        assert_eq!(server.message_buffer_cache.lock().unwrap().len(), 0);
+
+        let response = query(0)?;
+        assert!(matches!(response.resources[..], [DnsRecord::OPT { flags: 0, .. }]));
+        assert_eq!(server.message_buffer_cache.lock().unwrap().len(), 1);
+        Ok(())
     }
 }
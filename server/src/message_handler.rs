@@ -1,15 +1,30 @@
 extern crate base32;
-extern crate regex;
 
-use std::{borrow::Borrow, collections::VecDeque};
-use std::{collections::HashMap};
+use std::{collections::VecDeque};
+use std::{collections::{HashMap, HashSet}};
 use std::convert::TryInto;
-use regex::Regex;
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant, SystemTime};
+
+use lru::LruCache;
+
+use crate::zone::DomainName;
 
 type Error = Box<dyn std::error::Error>;
 type Result<T> = std::result::Result<T, Error>;
 
 const RFC4648_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+// How long to remember delivered messages. Resolvers give up retrying well
+// within this, and devices pick a new ID every boot
+const COMPLETED_ID_WINDOW: Duration = Duration::from_secs(60 * 60);
+// How long to keep refusing chunks of a quarantined message after its buffer
+// is gone, so the colliding devices' retransmissions can't start it afresh
+const QUARANTINE_WINDOW: Duration = Duration::from_secs(60 * 60);
+// Devices send every chunk of a message within seconds, anything quiet for
+// this long has lost chunks for good
+const DEFAULT_MESSAGE_TTL: Duration = Duration::from_secs(10 * 60);
+const DEFAULT_MEMORY_BUDGET: usize = 16 * 1024 * 1024;
 
 fn checksum(header: &str) -> bool {
     let mut check: usize = 0;
@@ -19,44 +34,78 @@ fn checksum(header: &str) -> bool {
             None => { return false; }
         }
     }
-    println!("check {:?}", check);
     if check == 0 { return true }
     false
 }
 
-#[derive(Debug)]
+/// The device part of a message ID, see `MessageChunk::device_id`
+pub fn device_id_of(message_id: &str) -> &str {
+    &message_id[..8]
+}
+
+/// Devices end message IDs with their boot count, modulo 32, after the
+/// random part
+pub fn boot_counter_of(message_id: &str) -> Option<u8> {
+    let last = *message_id.as_bytes().last()?;
+    RFC4648_ALPHABET.iter().position(|c| *c == last).map(|idx| idx as u8)
+}
+
+// Validate the 16 character preamble of a message, [Version 1][Index 1][UniqueID 13][Checksum 1]
+fn parse_header(source: &str) -> Result<(char, u8)> {
+    // Sliced by byte below, which only lines up with characters in ASCII
+    if !source.is_ascii() {
+        return Err("Not a valid message".into())
+    }
+    if source.len() < 16 {
+        return Err("Invalid Message: too short".into())
+    }
+
+    let version = source[0..1].chars().next().unwrap();
+    if !(version == 'A' || version == 'B') {
+        return Err("Not a valid message".into())
+    }
+
+    let idx_byte = source[1..2].chars().next().unwrap() as usize;
+    let idx: u8 = RFC4648_ALPHABET.iter().position(|c| idx_byte == *c as usize)
+        .ok_or("Unable to decode index")?
+        .try_into().unwrap();
+
+    if !checksum(&source[0..16]) {
+        return Err("Not a valid message".into())
+    }
+
+    Ok((version, idx))
+}
+
+#[derive(Debug, Clone)]
 pub struct MessageChunk {
     pub source: String,
     pub idx: u8,
+    #[allow(dead_code)]
     pub version: char,
     pub last: bool,
+    // The zone the chunk arrived on
+    pub zone: DomainName,
 }
 
 impl MessageChunk {
 
-    pub fn from(raw_question: &str, domain: &str) -> Result<Self> {
-        let domain_rg = Regex::new(&format!("{}$", domain))?;
-        let period_rg = Regex::new(r"\.+")?;
-        let raw_message = domain_rg.replace(raw_question, "");
-        let source = period_rg.replace_all(raw_message.borrow(), "").to_ascii_uppercase();
+    pub fn from(raw_question: &str, zone: &DomainName) -> Result<Self> {
+        // Questions arrive in whatever case the resolver chose (DNS 0x20),
+        // base32 doesn't care so neither do we
+        let question = DomainName::new(raw_question);
+        let source = question.strip_suffix(zone)
+            .ok_or("Question is outside of zone")?
+            .concat()
+            .to_ascii_uppercase();
 
-        if source.len() < 16 {
-            return Err("Invalid Message: too short".into())
-        }
-
-        let version = source[0..1].chars().next().unwrap();
-        if !(version == 'A' || version == 'B') {
-            return Err("Not a valid message".into())
-        }
-
-        let idx_byte = source[1..2].chars().next().unwrap() as usize;
-        let idx: u8 = RFC4648_ALPHABET.iter().position(|c| idx_byte == *c as usize)
-            .ok_or("Unable to decode index")?
-            .try_into().unwrap();
+        MessageChunk::from_source(source, zone.clone())
+    }
 
-        if !checksum(&source[0..16]) {
-            return Err("Not a valid message".into())
-        }
+    /// Rebuild a chunk from its header and payload with the zone stripped,
+    /// as kept in `source`
+    pub fn from_source(source: String, zone: DomainName) -> Result<Self> {
+        let (version, idx) = parse_header(&source)?;
 
         let mut last = false;
         if version == 'B' {
@@ -68,45 +117,188 @@ impl MessageChunk {
             idx,
             last,
             version,
+            zone,
         })
     }
 
+    /// Whether the leftmost label of `raw_question` starts with a valid chunk
+    /// header. Resolvers doing QNAME minimisation (RFC 7816/9156) first ask for
+    /// the names above it, which only carry payload labels.
+    pub fn has_header(raw_question: &str) -> bool {
+        let label = raw_question.split('.').next().unwrap_or("");
+        parse_header(&label.to_ascii_uppercase()).is_ok()
+    }
+
     pub fn id(&self) -> String {
         self.source[2..15].to_string()
     }
 
+    pub fn key(&self) -> MessageKey {
+        MessageKey { zone: self.zone.clone(), id: self.id() }
+    }
+
+    /// The fixed part of the ID burnt into each device, the rest changes every boot
+    pub fn device_id(&self) -> String {
+        self.source[2..10].to_string()
+    }
+
     pub fn content(&self) -> String {
         self.source[16..].to_string()
     }
 
 }
 
+/// What messages are told apart by. IDs are only unique within a zone, and
+/// chunks sent through one zone must never complete a message in another
+/// which might only allow certain devices.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MessageKey {
+    pub zone: DomainName,
+    pub id: String,
+}
+
+impl fmt::Display for MessageKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} on {}", self.id, self.zone)
+    }
+}
+
+/// Two chunks which can't belong to the same message arrived under one ID, most
+/// likely two devices (or two boots of one) which picked the same random part
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkConflict {
+    pub id: String,
+    pub idx: u8,
+    pub reason: String,
+    // Every version of every chunk seen for the message, by index
+    pub versions: Vec<(u8, Vec<String>)>,
+}
+
+impl fmt::Display for ChunkConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Conflicting chunk {} for message {}: {}", self.idx, self.id, self.reason)
+    }
+}
+
+impl std::error::Error for ChunkConflict {}
+
 #[derive(Debug)]
 pub struct MessageBuffer {
     message_parts: HashMap<u8, MessageChunk>,
     message_parts_total_len: u8,
+    // Second versions of chunks which disagreed with the first
+    conflicting_parts: HashMap<u8, MessageChunk>,
+    // Set on the first conflict, a quarantined message never completes
+    quarantined: bool,
+    // Handed out by `take_completed` and being written out, kept until
+    // that's confirmed so a crash part way through can deliver it again
+    taken: bool,
+    first_seen: Instant,
+    last_seen: Instant,
 }
 
 impl MessageBuffer {
 
     pub fn new() -> Self {
+        let now = Instant::now();
         MessageBuffer {
             message_parts: HashMap::new(),
             message_parts_total_len: 0,
+            conflicting_parts: HashMap::new(),
+            quarantined: false,
+            taken: false,
+            first_seen: now,
+            last_seen: now,
+        }
+    }
+
+    /// Rough number of bytes held, counted against the cache's memory budget
+    pub fn size(&self) -> usize {
+        self.message_parts.values()
+            .chain(self.conflicting_parts.values())
+            .map(|chunk| chunk.source.len())
+            .sum()
+    }
+
+    /// Indexes not received yet. Without the last chunk we only know about
+    /// the gaps before the highest index seen.
+    pub fn missing_chunks(&self) -> Vec<u8> {
+        let end = match self.message_parts_total_len {
+            0 => self.message_parts.keys().max().map_or(0, |max| max + 1),
+            total => total,
+        };
+        (0..end).filter(|idx| !self.message_parts.contains_key(idx)).collect()
+    }
+
+    /// Add a chunk, returning whether the message is complete. Errors with a
+    /// `ChunkConflict` if the chunk doesn't fit the ones already received.
+    pub fn insert(&mut self, message_chunk: MessageChunk) -> Result<bool> {
+        let idx = message_chunk.idx;
+        let id = message_chunk.id();
+        let conflict = self.find_conflict(&message_chunk);
+
+        // Keep every version around for the quarantine report
+        if conflict.is_some() && self.message_parts.contains_key(&idx) {
+            self.conflicting_parts.entry(idx).or_insert(message_chunk);
+        } else {
+            if message_chunk.last && conflict.is_none() {
+                self.message_parts_total_len = idx + 1;
+            }
+            self.message_parts.entry(idx).or_insert(message_chunk);
+        }
+
+        match conflict {
+            Some(reason) if !self.quarantined => {
+                self.quarantined = true;
+                Err(ChunkConflict { id, idx, reason, versions: self.versions() }.into())
+            }
+            _ if self.quarantined => Err(format!("Message {} is quarantined", id).into()),
+            _ => Ok(self.is_complete()),
         }
     }
 
-    pub fn insert(&mut self, message_chunk: MessageChunk) -> bool {
+    fn find_conflict(&self, message_chunk: &MessageChunk) -> Option<String> {
         let idx = message_chunk.idx;
+        if let Some(existing) = self.message_parts.get(&idx) {
+            if existing.source != message_chunk.source {
+                return Some("payload differs from the chunk already received".to_string());
+            }
+        }
+        if self.message_parts_total_len > 0 {
+            let last_idx = self.message_parts_total_len - 1;
+            if idx > last_idx || (message_chunk.last && idx != last_idx) {
+                return Some(format!("message already ended at chunk {}", last_idx));
+            }
+        }
         if message_chunk.last {
-            self.message_parts_total_len = idx + 1;
+            if let Some(beyond) = self.message_parts.keys().find(|&&other| other > idx) {
+                return Some(format!("chunk {} was already received past the end", beyond));
+            }
         }
-        self.message_parts.insert(idx, message_chunk);
-        self.is_complete()
+        None
+    }
+
+    pub fn is_quarantined(&self) -> bool {
+        self.quarantined
     }
 
     pub fn is_complete(&self) -> bool {
-        self.message_parts_total_len as usize == self.message_parts.len()
+        !self.is_quarantined() && self.message_parts_total_len as usize == self.message_parts.len()
+    }
+
+    /// Every version of every chunk received, by index
+    pub fn versions(&self) -> Vec<(u8, Vec<String>)> {
+        let mut keys = self.message_parts.keys().copied().collect::<Vec<u8>>();
+        keys.sort_unstable();
+        keys.into_iter()
+            .map(|key| {
+                let mut versions = vec![self.message_parts[&key].content()];
+                if let Some(conflicting) = self.conflicting_parts.get(&key) {
+                    versions.push(conflicting.content());
+                }
+                (key, versions)
+            })
+            .collect()
     }
 
     pub fn get_message(&self) -> String {
@@ -122,93 +314,658 @@ impl MessageBuffer {
 
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionReason {
+    // Pushed out by newer messages once the cache was full
+    Capacity,
+    // Nothing received for longer than the message TTL
+    Expired,
+    // Pushed out to keep the cache under its memory budget
+    MemoryBudget,
+}
+
+/// A message dropped from the cache before it was delivered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvictedMessage {
+    pub id: String,
+    pub zone: DomainName,
+    pub reason: EvictionReason,
+    pub received: Vec<u8>,
+    pub missing: Vec<u8>,
+    // Whether the last chunk arrived, if not more than `missing` may be lost
+    pub last_received: bool,
+    // Only expiry drops complete messages, ones which failed to be written
+    // out and saw no retransmission to try again
+    pub complete: bool,
+    pub age: Duration,
+}
+
+/// The cache's contents along with when each part was received, see
+/// `MessageBufferCache::snapshot`
 #[derive(Debug)]
+pub struct CacheSnapshot {
+    pub chunks: Vec<(SystemTime, MessageChunk)>,
+    pub completed: Vec<(SystemTime, MessageKey)>,
+    pub quarantined: Vec<(SystemTime, MessageKey)>,
+}
+
+pub type EvictionCallback = Box<dyn FnMut(&EvictedMessage) + Send>;
+
 pub struct MessageBufferCache {
-    message_buffers: HashMap<String, MessageBuffer>,
-    cache_size: usize,
-    buffer_list: VecDeque<String>,
+    // Ordered by the last time a chunk arrived, least recent first out
+    message_buffers: LruCache<MessageKey, MessageBuffer>,
+    message_ttl: Duration,
+    memory_budget: usize,
+    memory_used: usize,
+    on_evict: Option<EvictionCallback>,
+    // Messages confirmed delivered, oldest first, so chunks retransmitted by
+    // resolvers don't deliver the message again
+    completed_ids: HashSet<MessageKey>,
+    completed_list: VecDeque<(Instant, MessageKey)>,
+    completed_window: Duration,
+    // Messages which had conflicting chunks, oldest first, refused until
+    // `quarantine_window` has passed
+    quarantined_ids: HashSet<MessageKey>,
+    quarantined_list: VecDeque<(Instant, MessageKey)>,
+    quarantine_window: Duration,
+}
+
+impl fmt::Debug for MessageBufferCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MessageBufferCache")
+            .field("message_buffers", &self.message_buffers.len())
+            .field("cache_size", &self.message_buffers.cap())
+            .field("memory_used", &self.memory_used)
+            .field("completed_ids", &self.completed_ids.len())
+            .field("quarantined_ids", &self.quarantined_ids.len())
+            .finish()
+    }
 }
 
 impl MessageBufferCache {
     pub fn new(cache_size: usize) -> Self {
-        let message_buffers = HashMap::new();
+        let cache_size = NonZeroUsize::new(cache_size).unwrap_or(NonZeroUsize::MIN);
         MessageBufferCache {
-            message_buffers,
-            cache_size,
-            buffer_list: VecDeque::new()
+            message_buffers: LruCache::new(cache_size),
+            message_ttl: DEFAULT_MESSAGE_TTL,
+            memory_budget: DEFAULT_MEMORY_BUDGET,
+            memory_used: 0,
+            on_evict: None,
+            completed_ids: HashSet::new(),
+            completed_list: VecDeque::new(),
+            completed_window: COMPLETED_ID_WINDOW,
+            quarantined_ids: HashSet::new(),
+            quarantined_list: VecDeque::new(),
+            quarantine_window: QUARANTINE_WINDOW,
         }
     }
 
+    /// Drop partial messages which haven't seen a chunk for this long
+    pub fn with_message_ttl(mut self, message_ttl: Duration) -> Self {
+        self.message_ttl = message_ttl;
+        self
+    }
+
+    /// Evict the least recently used messages once the chunks held add up to
+    /// more than this many bytes
+    pub fn with_memory_budget(mut self, memory_budget: usize) -> Self {
+        self.memory_budget = memory_budget;
+        self
+    }
+
+    /// Called with every message evicted before it completed
+    pub fn on_evict(mut self, on_evict: EvictionCallback) -> Self {
+        self.on_evict = Some(on_evict);
+        self
+    }
+
+    /// Add a chunk, returning whether its message is complete. Chunks of a
+    /// message which was already delivered report complete straight away,
+    /// those of a quarantined one are refused.
     pub fn add(&mut self, message_chunk: MessageChunk) -> Result<bool> {
-        let message_id = message_chunk.id();
-        let message_buffer = self.message_buffers.entry(message_id.clone()).
-            or_insert_with(MessageBuffer::new);
-        let is_complete = message_buffer.insert(message_chunk);
-        if self.buffer_list.iter().find(|&id| id == &message_id) == None {
-            self.buffer_list.push_front(message_id);
-        };
+        self.expire_remembered();
+        self.expire();
+        let key = message_chunk.key();
+        if self.completed_ids.contains(&key) {
+            return Ok(true);
+        }
+        if self.quarantined_ids.contains(&key) && !self.message_buffers.contains(&key) {
+            return Err(format!("Message {} is quarantined", key).into());
+        }
+        self.insert(message_chunk, Instant::now())
+    }
+
+    fn insert(&mut self, message_chunk: MessageChunk, seen: Instant) -> Result<bool> {
+        let key = message_chunk.key();
+        if !self.message_buffers.contains(&key) {
+            if self.message_buffers.len() == self.message_buffers.cap().get() {
+                let victim = self.least_recent_incomplete(&key)
+                    .ok_or("Every message held is complete and waiting to be written out")?;
+                let evicted = self.message_buffers.pop(&victim).unwrap();
+                self.evicted(victim, evicted, EvictionReason::Capacity);
+            }
+            self.message_buffers.put(key.clone(), MessageBuffer::new());
+        }
+        // Promotes the message to most recently used
+        let message_buffer = self.message_buffers.get_mut(&key).unwrap();
+        let size_before = message_buffer.size();
+        message_buffer.first_seen = message_buffer.first_seen.min(seen);
+        message_buffer.last_seen = seen;
+        let inserted = message_buffer.insert(message_chunk);
+        self.memory_used = self.memory_used - size_before + message_buffer.size();
+        if message_buffer.is_quarantined() && self.quarantined_ids.insert(key.clone()) {
+            self.quarantined_list.push_back((seen, key.clone()));
+        }
+
+        while self.memory_used > self.memory_budget {
+            let victim = match self.least_recent_incomplete(&key) {
+                Some(victim) => victim,
+                None => break,
+            };
+            let evicted = self.message_buffers.pop(&victim).unwrap();
+            self.evicted(victim, evicted, EvictionReason::MemoryBudget);
+        }
+        inserted
+    }
+
+    /// The partial message to make room by dropping, never `except`, the one
+    /// just added to. Complete ones are waiting to be taken or written out.
+    fn least_recent_incomplete(&self, except: &MessageKey) -> Option<MessageKey> {
+        self.message_buffers.iter()
+            .rev()
+            .find(|(key, message_buffer)| *key != except && !message_buffer.is_complete())
+            .map(|(key, _)| key.clone())
+    }
+
+    /// Drop messages which have gone quiet for longer than the TTL. Called on
+    /// every add, and should be called periodically for when nothing arrives.
+    /// Messages being written out are left alone.
+    pub fn expire(&mut self) {
+        let expired: Vec<MessageKey> = self.message_buffers.iter()
+            .rev()
+            .take_while(|(_, message_buffer)| message_buffer.last_seen.elapsed() >= self.message_ttl)
+            .filter(|(_, message_buffer)| !message_buffer.taken)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            let evicted = self.message_buffers.pop(&key).unwrap();
+            self.evicted(key, evicted, EvictionReason::Expired);
+        }
+    }
 
-        // TODO: More efficient LRU like pattern. Works for now
-        if self.buffer_list.len() > self.cache_size {
-            let evicted_key  = self.buffer_list.pop_back().unwrap();
-            self.message_buffers.remove(&evicted_key);
+    fn evicted(&mut self, key: MessageKey, message_buffer: MessageBuffer, reason: EvictionReason) {
+        self.memory_used -= message_buffer.size();
+        if let Some(on_evict) = self.on_evict.as_mut() {
+            let mut received = message_buffer.message_parts.keys().copied().collect::<Vec<u8>>();
+            received.sort_unstable();
+            on_evict(&EvictedMessage {
+                id: key.id,
+                zone: key.zone,
+                reason,
+                received,
+                missing: message_buffer.missing_chunks(),
+                last_received: message_buffer.message_parts_total_len > 0,
+                complete: message_buffer.is_complete(),
+                age: message_buffer.first_seen.elapsed(),
+            });
         }
-        Ok(is_complete)
     }
-    
-    pub fn get_value(&self, key: &str) -> Option<Vec<u8>> {
-        match self.message_buffers.get(key) {
+
+    pub fn get_value(&self, key: &MessageKey) -> Option<Vec<u8>> {
+        match self.message_buffers.peek(key) {
             Some(val) => base32::decode(base32::Alphabet::RFC4648 { padding: false }, val.get_message().as_str()),
             None => None,
         }
     }
+
+    /// Partial messages held, complete ones until they're taken
+    pub fn len(&self) -> usize {
+        self.message_buffers.len()
+    }
+
+    /// Bytes of chunk data held, what the memory budget is measured against
+    pub fn memory_used(&self) -> usize {
+        self.memory_used
+    }
+
+    /// How many distinct chunks of a message have arrived
+    pub fn chunks_received(&self, key: &MessageKey) -> Option<usize> {
+        Some(self.message_buffers.peek(key)?.message_parts.len())
+    }
+
+    /// When the first and latest chunks of a message arrived
+    pub fn received_between(&self, key: &MessageKey) -> Option<(SystemTime, SystemTime)> {
+        let message_buffer = self.message_buffers.peek(key)?;
+        Some((system_time_at(message_buffer.first_seen), system_time_at(message_buffer.last_seen)))
+    }
+
+    /// Hand out a completed message once, later calls return None. The buffer
+    /// is kept until `confirm_delivered` or `release` says how writing it out went.
+    pub fn take_completed(&mut self, key: &MessageKey) -> Option<Vec<u8>> {
+        let message_buffer = self.message_buffers.peek_mut(key)?;
+        if !message_buffer.is_complete() || message_buffer.taken {
+            return None;
+        }
+        message_buffer.taken = true;
+        self.get_value(key)
+    }
+
+    /// A taken message was written out. The buffer is dropped and its ID
+    /// remembered for `completed_window`.
+    pub fn confirm_delivered(&mut self, key: &MessageKey) {
+        if let Some(message_buffer) = self.message_buffers.pop(key) {
+            self.memory_used -= message_buffer.size();
+        }
+        if self.completed_ids.insert(key.clone()) {
+            self.completed_list.push_back((Instant::now(), key.clone()));
+        }
+    }
+
+    /// Writing out a taken message failed, hand it out again on the next
+    /// retransmission of one of its chunks
+    pub fn release(&mut self, key: &MessageKey) {
+        if let Some(message_buffer) = self.message_buffers.peek_mut(key) {
+            message_buffer.taken = false;
+        }
+    }
+
+    /// Put back a chunk read from the journal, received at `at`. Conflicts
+    /// are quarantined again but not reported, that happened the first time.
+    /// Returns whether the chunk was kept.
+    pub fn restore(&mut self, message_chunk: MessageChunk, at: SystemTime) -> bool {
+        let key = message_chunk.key();
+        if self.completed_ids.contains(&key) {
+            return false;
+        }
+        // Retransmissions refused while the message was quarantined
+        if self.quarantined_ids.contains(&key) && !self.message_buffers.contains(&key) {
+            return false;
+        }
+        let _ = self.insert(message_chunk, instant_at(at));
+        true
+    }
+
+    /// Remember a message quarantined at `at` before the restart
+    pub fn restore_quarantined(&mut self, key: &MessageKey, at: SystemTime) {
+        let quarantined_at = instant_at(at);
+        if quarantined_at.elapsed() >= self.quarantine_window {
+            return;
+        }
+        if let Some(message_buffer) = self.message_buffers.peek_mut(key) {
+            message_buffer.quarantined = true;
+        }
+        if self.quarantined_ids.insert(key.clone()) {
+            self.quarantined_list.push_back((quarantined_at, key.clone()));
+        }
+    }
+
+    /// Remember a message delivered at `at` before the restart
+    pub fn restore_completed(&mut self, key: &MessageKey, at: SystemTime) {
+        let completed_at = instant_at(at);
+        if completed_at.elapsed() >= self.completed_window {
+            return;
+        }
+        if let Some(message_buffer) = self.message_buffers.pop(key) {
+            self.memory_used -= message_buffer.size();
+        }
+        if self.completed_ids.insert(key.clone()) {
+            self.completed_list.push_back((completed_at, key.clone()));
+        }
+    }
+
+    /// Messages whose last chunk arrived but which were never handed out
+    pub fn pending_completed(&self) -> Vec<MessageKey> {
+        self.message_buffers.iter()
+            .filter(|(_, message_buffer)| message_buffer.is_complete())
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Everything needed to rebuild the cache: every chunk held, least
+    /// recently used message first, and the IDs of delivered and
+    /// quarantined messages
+    pub fn snapshot(&self) -> CacheSnapshot {
+        let mut chunks = Vec::new();
+        for (_, message_buffer) in self.message_buffers.iter().rev() {
+            let mut parts = message_buffer.message_parts.values()
+                .chain(message_buffer.conflicting_parts.values())
+                .collect::<Vec<&MessageChunk>>();
+            parts.sort_by_key(|chunk| chunk.idx);
+            // Only the first and latest arrival times are kept per message
+            for (i, chunk) in parts.into_iter().enumerate() {
+                let seen = if i == 0 { message_buffer.first_seen } else { message_buffer.last_seen };
+                chunks.push((system_time_at(seen), chunk.clone()));
+            }
+        }
+        let completed = self.completed_list.iter()
+            .map(|(completed_at, key)| (system_time_at(*completed_at), key.clone()))
+            .collect();
+        let quarantined = self.quarantined_list.iter()
+            .map(|(quarantined_at, key)| (system_time_at(*quarantined_at), key.clone()))
+            .collect();
+        CacheSnapshot { chunks, completed, quarantined }
+    }
+
+    /// Forget delivered and quarantined messages once their windows have passed
+    fn expire_remembered(&mut self) {
+        while let Some((completed_at, _)) = self.completed_list.front() {
+            if completed_at.elapsed() < self.completed_window {
+                break;
+            }
+            let (_, key) = self.completed_list.pop_front().unwrap();
+            self.completed_ids.remove(&key);
+        }
+        while let Some((quarantined_at, _)) = self.quarantined_list.front() {
+            if quarantined_at.elapsed() < self.quarantine_window {
+                break;
+            }
+            let (_, key) = self.quarantined_list.pop_front().unwrap();
+            self.quarantined_ids.remove(&key);
+        }
+    }
+}
+
+/// The wall clock time `at` as an Instant, so restored state ages as if
+/// the server had been running all along
+fn instant_at(at: SystemTime) -> Instant {
+    let elapsed = at.elapsed().unwrap_or_default();
+    Instant::now().checked_sub(elapsed).unwrap_or_else(Instant::now)
+}
+
+fn system_time_at(instant: Instant) -> SystemTime {
+    SystemTime::now() - instant.elapsed()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn key(id: &str) -> MessageKey {
+        MessageKey { zone: "foo.co".into(), id: id.to_string() }
+    }
 
     #[test]
     fn test_ring_hash() -> Result<()> {
         let mut message_buffer_cache = MessageBufferCache::new(3);
         
-        message_buffer_cache.add(MessageChunk::from("AAAAAAAAAAAAAAAAPMRGM33PEI5.foo.co", "foo.co")?)?;
-        message_buffer_cache.add(MessageChunk::from("BBAAAAAAAAAAAAAACEYTBOIRH2.foo.co", "foo.co")?)?;
-        message_buffer_cache.add(MessageChunk::from("AA22222222222222FOO.foo.co", "foo.co")?)?;
-        message_buffer_cache.add(MessageChunk::from("AA33333333333333FOO.foo.co", "foo.co")?)?;
-        message_buffer_cache.add(MessageChunk::from("AA44444444444444Foo.foo.co", "foo.co")?)?;
+        message_buffer_cache.add(MessageChunk::from("AAAAAAAAAAAAAAAAPMRGM33PEI5.foo.co", &"foo.co".into())?)?;
+        message_buffer_cache.add(MessageChunk::from("BBAAAAAAAAAAAAAACEYTBOIRH2.foo.co", &"foo.co".into())?)?;
+        message_buffer_cache.add(MessageChunk::from("AA22222222222222FOO.foo.co", &"foo.co".into())?)?;
+        message_buffer_cache.add(MessageChunk::from("AA33333333333333FOO.foo.co", &"foo.co".into())?)?;
+        message_buffer_cache.add(MessageChunk::from("AA44444444444444Foo.foo.co", &"foo.co".into())?)?;
         assert!(message_buffer_cache.message_buffers.len() == 3);
-        assert!(!message_buffer_cache.message_buffers.contains_key("AAAAAAAAAAAAA"));
-        assert!(message_buffer_cache.message_buffers.contains_key("2222222222222"));
+        // The complete message waits to be taken, the oldest partial one goes
+        assert!(message_buffer_cache.message_buffers.contains(&key("AAAAAAAAAAAAA")));
+        assert!(!message_buffer_cache.message_buffers.contains(&key("2222222222222")));
         Ok(())
     }
 
+    #[test]
+    fn test_outside_zone() {
+        assert!(MessageChunk::from("AADDDDDDDDDDDDDDPMRGM33PEI5.foo.co", &"i.mdp.im".into()).is_err());
+        assert!(MessageChunk::from("AADDDDDDDDDDDDDDPMRGM33PEI5.fooxco", &"foo.co".into()).is_err());
+        assert!(MessageChunk::from("AADDDDDDDDDDDDDDPMRGM33PEI5.xfoo.co", &"foo.co".into()).is_err());
+    }
+
+    #[test]
+    fn test_message_id_parts() {
+        assert_eq!(device_id_of("ZACKAAAAQX2MD"), "ZACKAAAA");
+        assert_eq!(boot_counter_of("ZACKAAAAQX2MD"), Some(3));
+        assert_eq!(boot_counter_of("ZACKAAAAQX2M7"), Some(31));
+    }
+
+    #[test]
+    fn test_has_header() {
+        assert!(MessageChunk::has_header("AADDDDDDDDDDDDDDPMRGM33PEI5.foo.co"));
+        assert!(MessageChunk::has_header("baco3mbpmwrwi2nwdeabcmtiiorarpaccezgqq5cbc6fjpxx4q23abv3rqhw6l6.3waa3ssck5evx3aabxfeev2jlpwbadoiabvtq36iuag3nz3yjwb6cecftaagwod.i.mdp.im"));
+        assert!(!MessageChunk::has_header("pzcua3c4b2zouzn5ygv5jxiylsmj2wg23teblwsrtj.i.mdp.im"));
+        assert!(!MessageChunk::has_header("3waa3ssck5evx3aabxfeev2jlpwbadoiabvtq36iuag3nz3yjwb6cecftaagwod.pzcua3c4b2zouzn5ygv5jxiylsmj2wg23teblwsrtj.i.mdp.im"));
+        assert!(!MessageChunk::has_header("i.mdp.im"));
+        // Multi-byte characters straddling where the header is sliced
+        assert!(!MessageChunk::has_header("Aé3waa3ssck5evx3aab.i.mdp.im"));
+        assert!(!MessageChunk::has_header("AADDDDDDDDDDDDDDé.i.mdp.im"));
+        assert!(MessageChunk::from("AADDDDDDDDDDDDDé.foo.co", &"foo.co".into()).is_err());
+    }
+
     #[test]
     fn test_message_building() -> Result<()> {
         let mut message_buffer_cache = MessageBufferCache::new(3);
-        message_buffer_cache.add(MessageChunk::from("AADDDDDDDDDDDDDDPMRGM33PEI5.foo.co", "foo.co")?)?;
-        let is_complete = message_buffer_cache.add(MessageChunk::from("BBDDDDDDDDDDDDDDCEYTBOIRH2.foo.co", "foo.co")?)?;
+        message_buffer_cache.add(MessageChunk::from("AADDDDDDDDDDDDDDPMRGM33PEI5.foo.co", &"foo.co".into())?)?;
+        let is_complete = message_buffer_cache.add(MessageChunk::from("BBDDDDDDDDDDDDDDCEYTBOIRH2.foo.co", &"foo.co".into())?)?;
         assert!(is_complete);
-        let byte_message = message_buffer_cache.get_value("DDDDDDDDDDDDD").unwrap();
+        let byte_message = message_buffer_cache.get_value(&key("DDDDDDDDDDDDD")).unwrap();
         let message = String::from_utf8(byte_message).unwrap();
         assert_eq!(message, String::from("{\"foo\":\"bar\"}"));
         Ok(())
     }
 
+    #[test]
+    fn test_mixed_case_question() -> Result<()> {
+        let mut message_buffer_cache = MessageBufferCache::new(3);
+        message_buffer_cache.add(MessageChunk::from("aAdDDddDdDDdDDdDpMrGm33pEi5.FoO.cO", &"foo.co".into())?)?;
+        let is_complete = message_buffer_cache.add(MessageChunk::from("BbDdDDDDDDDDDDDDceytboirh2.foo.CO", &"foo.co".into())?)?;
+        assert!(is_complete);
+        let byte_message = message_buffer_cache.get_value(&key("DDDDDDDDDDDDD")).unwrap();
+        assert_eq!(String::from_utf8(byte_message)?, String::from("{\"foo\":\"bar\"}"));
+        Ok(())
+    }
+
     #[test]
     fn test_message_dupes() -> Result<()> {
         // Should happily handle multiple duplicate messages an in any order (UDP constrain)
         let mut message_buffer_cache = MessageBufferCache::new(3);
-        let mut is_complete =message_buffer_cache.add(MessageChunk::from("AAZ222222222222ZPMRGM33PEI5.foo.co", "foo.co")?)?;
+        let mut is_complete =message_buffer_cache.add(MessageChunk::from("AAZ222222222222ZPMRGM33PEI5.foo.co", &"foo.co".into())?)?;
         assert!(!is_complete);
-        is_complete = message_buffer_cache.add(MessageChunk::from("BBZ222222222222ZCEYTBOIRH2.foo.co", "foo.co")?)?;
+        is_complete = message_buffer_cache.add(MessageChunk::from("BBZ222222222222ZCEYTBOIRH2.foo.co", &"foo.co".into())?)?;
         assert!(is_complete);
-        is_complete = message_buffer_cache.add(MessageChunk::from("BBZ222222222222ZCEYTBOIRH2.foo.co", "foo.co")?)?;
+        is_complete = message_buffer_cache.add(MessageChunk::from("BBZ222222222222ZCEYTBOIRH2.foo.co", &"foo.co".into())?)?;
         assert!(is_complete);
-        let byte_message = message_buffer_cache.get_value("Z222222222222").unwrap();
+        let byte_message = message_buffer_cache.get_value(&key("Z222222222222")).unwrap();
         let message = String::from_utf8(byte_message)?;
         assert_eq!(message, String::from("{\"foo\":\"bar\"}"));
         Ok(())
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_same_id_on_two_zones() -> Result<()> {
+        // The start of a message sent through one zone can't be finished
+        // through another, each zone reassembles its own
+        let mut message_buffer_cache = MessageBufferCache::new(3);
+        message_buffer_cache.add(MessageChunk::from("AADDDDDDDDDDDDDDPMRGM33PEI5.bar.co", &"bar.co".into())?)?;
+        assert!(!message_buffer_cache.add(MessageChunk::from("BBDDDDDDDDDDDDDDCEYTBOIRH2.foo.co", &"foo.co".into())?)?);
+        assert_eq!(message_buffer_cache.take_completed(&key("DDDDDDDDDDDDD")), None);
+        assert_eq!(message_buffer_cache.len(), 2);
+
+        message_buffer_cache.add(MessageChunk::from("AADDDDDDDDDDDDDDPMRGM33PEI5.foo.co", &"foo.co".into())?)?;
+        assert!(message_buffer_cache.take_completed(&key("DDDDDDDDDDDDD")).is_some());
+        message_buffer_cache.confirm_delivered(&key("DDDDDDDDDDDDD"));
+        // Delivered on foo.co, still partial on bar.co
+        let bar = MessageKey { zone: "bar.co".into(), id: "DDDDDDDDDDDDD".to_string() };
+        assert!(!message_buffer_cache.add(MessageChunk::from("AADDDDDDDDDDDDDDPMRGM33PEI5.bar.co", &"bar.co".into())?)?);
+        assert_eq!(message_buffer_cache.take_completed(&bar), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_delivered_once() -> Result<()> {
+        let mut message_buffer_cache = MessageBufferCache::new(3);
+        message_buffer_cache.add(MessageChunk::from("AAZ222222222222ZPMRGM33PEI5.foo.co", &"foo.co".into())?)?;
+        assert_eq!(message_buffer_cache.take_completed(&key("Z222222222222")), None);
+        assert!(message_buffer_cache.add(MessageChunk::from("BBZ222222222222ZCEYTBOIRH2.foo.co", &"foo.co".into())?)?);
+        let byte_message = message_buffer_cache.take_completed(&key("Z222222222222")).unwrap();
+        assert_eq!(String::from_utf8(byte_message)?, String::from("{\"foo\":\"bar\"}"));
+        // Only handed out once while it's being written out
+        assert_eq!(message_buffer_cache.take_completed(&key("Z222222222222")), None);
+        message_buffer_cache.confirm_delivered(&key("Z222222222222"));
+
+        // Retransmitted chunks are still acknowledged as complete, but the message
+        // is only handed out once, even after the buffer would have been evicted
+        for id in &["AA33333333333333FOO.foo.co", "AA44444444444444FOO.foo.co", "AA55555555555555FOO.foo.co"] {
+            message_buffer_cache.add(MessageChunk::from(id, &"foo.co".into())?)?;
+        }
+        assert!(message_buffer_cache.add(MessageChunk::from("BBZ222222222222ZCEYTBOIRH2.foo.co", &"foo.co".into())?)?);
+        assert!(message_buffer_cache.add(MessageChunk::from("AAZ222222222222ZPMRGM33PEI5.foo.co", &"foo.co".into())?)?);
+        assert_eq!(message_buffer_cache.take_completed(&key("Z222222222222")), None);
+        assert!(!message_buffer_cache.message_buffers.contains(&key("Z222222222222")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_release() -> Result<()> {
+        let mut message_buffer_cache = MessageBufferCache::new(3);
+        message_buffer_cache.add(MessageChunk::from("BAZ222222222222YPMRGM33PEI5.foo.co", &"foo.co".into())?)?;
+        assert!(message_buffer_cache.take_completed(&key("Z222222222222")).is_some());
+        // Writing it out failed, it's still pending and the next retransmission gets it
+        message_buffer_cache.release(&key("Z222222222222"));
+        assert_eq!(message_buffer_cache.pending_completed(), vec![key("Z222222222222")]);
+        assert!(message_buffer_cache.add(MessageChunk::from("BAZ222222222222YPMRGM33PEI5.foo.co", &"foo.co".into())?)?);
+        assert!(message_buffer_cache.take_completed(&key("Z222222222222")).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_completed_window_expires() -> Result<()> {
+        let mut message_buffer_cache = MessageBufferCache::new(3);
+        message_buffer_cache.completed_window = Duration::from_secs(0);
+        message_buffer_cache.add(MessageChunk::from("BAZ222222222222YPMRGM33PEI5.foo.co", &"foo.co".into())?)?;
+        assert!(message_buffer_cache.take_completed(&key("Z222222222222")).is_some());
+        message_buffer_cache.confirm_delivered(&key("Z222222222222"));
+        message_buffer_cache.add(MessageChunk::from("AA33333333333333FOO.foo.co", &"foo.co".into())?)?;
+        assert!(message_buffer_cache.completed_ids.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflicting_chunks() -> Result<()> {
+        let mut message_buffer_cache = MessageBufferCache::new(3);
+        message_buffer_cache.add(MessageChunk::from("AADDDDDDDDDDDDDDPMRGM33PEI5.foo.co", &"foo.co".into())?)?;
+        let err = message_buffer_cache.add(MessageChunk::from("AADDDDDDDDDDDDDDPMRGM33PEI6.foo.co", &"foo.co".into())?).unwrap_err();
+        let conflict = err.downcast_ref::<ChunkConflict>().unwrap();
+        assert_eq!(conflict.id, "DDDDDDDDDDDDD");
+        assert_eq!(conflict.idx, 0);
+        assert_eq!(conflict.versions, vec![(0, vec!["PMRGM33PEI5".to_string(), "PMRGM33PEI6".to_string()])]);
+
+        // Quarantined messages never complete, later chunks are refused
+        let err = message_buffer_cache.add(MessageChunk::from("BBDDDDDDDDDDDDDDCEYTBOIRH2.foo.co", &"foo.co".into())?).unwrap_err();
+        assert!(err.downcast_ref::<ChunkConflict>().is_none());
+        assert_eq!(message_buffer_cache.take_completed(&key("DDDDDDDDDDDDD")), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_quarantine_outlives_buffer() -> Result<()> {
+        let mut message_buffer_cache = MessageBufferCache::new(1);
+        message_buffer_cache.add(MessageChunk::from("AADDDDDDDDDDDDDDPMRGM33PEI5.foo.co", &"foo.co".into())?)?;
+        assert!(message_buffer_cache.add(MessageChunk::from("AADDDDDDDDDDDDDDPMRGM33PEI6.foo.co", &"foo.co".into())?).is_err());
+        // Pushed out by another message, a fresh start for the ID is still refused
+        message_buffer_cache.add(MessageChunk::from("AA33333333333333FOO.foo.co", &"foo.co".into())?)?;
+        assert!(!message_buffer_cache.message_buffers.contains(&key("DDDDDDDDDDDDD")));
+        assert!(message_buffer_cache.add(MessageChunk::from("AADDDDDDDDDDDDDDPMRGM33PEI5.foo.co", &"foo.co".into())?).is_err());
+        assert!(message_buffer_cache.add(MessageChunk::from("BBDDDDDDDDDDDDDDCEYTBOIRH2.foo.co", &"foo.co".into())?).is_err());
+        assert_eq!(message_buffer_cache.take_completed(&key("DDDDDDDDDDDDD")), None);
+
+        // Until the window passes
+        message_buffer_cache.quarantine_window = Duration::from_secs(0);
+        message_buffer_cache.add(MessageChunk::from("AADDDDDDDDDDDDDDPMRGM33PEI5.foo.co", &"foo.co".into())?)?;
+        assert!(message_buffer_cache.add(MessageChunk::from("BBDDDDDDDDDDDDDDCEYTBOIRH2.foo.co", &"foo.co".into())?)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflicting_message_length() -> Result<()> {
+        let mut message_buffer = MessageBuffer::new();
+        assert!(!message_buffer.insert(MessageChunk::from("BCDDDDDDDDDDDDDAFOO.foo.co", &"foo.co".into())?)?);
+        let err = message_buffer.insert(MessageChunk::from("BBDDDDDDDDDDDDDDCEYTBOIRH2.foo.co", &"foo.co".into())?).unwrap_err();
+        assert!(err.downcast_ref::<ChunkConflict>().is_some());
+        assert!(message_buffer.is_quarantined());
+
+        let mut message_buffer = MessageBuffer::new();
+        assert!(!message_buffer.insert(MessageChunk::from("ACDDDDDDDDDDDDDBFOO.foo.co", &"foo.co".into())?)?);
+        assert!(message_buffer.insert(MessageChunk::from("BBDDDDDDDDDDDDDDCEYTBOIRH2.foo.co", &"foo.co".into())?).is_err());
+        assert!(!message_buffer.is_complete());
+        Ok(())
+    }
+
+    fn recording_cache(cache_size: usize) -> (MessageBufferCache, Arc<Mutex<Vec<EvictedMessage>>>) {
+        let evictions = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&evictions);
+        let message_buffer_cache = MessageBufferCache::new(cache_size)
+            .on_evict(Box::new(move |evicted| recorded.lock().unwrap().push(evicted.clone())));
+        (message_buffer_cache, evictions)
+    }
+
+    #[test]
+    fn test_lru_order() -> Result<()> {
+        let (mut message_buffer_cache, evictions) = recording_cache(2);
+        message_buffer_cache.add(MessageChunk::from("AA22222222222222FOO.foo.co", &"foo.co".into())?)?;
+        message_buffer_cache.add(MessageChunk::from("AA33333333333333FOO.foo.co", &"foo.co".into())?)?;
+        // A new chunk for the older message makes it the most recently used
+        message_buffer_cache.add(MessageChunk::from("AC2222222222222YFOO.foo.co", &"foo.co".into())?)?;
+        message_buffer_cache.add(MessageChunk::from("AA44444444444444FOO.foo.co", &"foo.co".into())?)?;
+        assert!(message_buffer_cache.message_buffers.contains(&key("2222222222222")));
+        assert!(!message_buffer_cache.message_buffers.contains(&key("3333333333333")));
+
+        let evictions = evictions.lock().unwrap();
+        assert_eq!(evictions.len(), 1);
+        assert_eq!(evictions[0].id, "3333333333333");
+        assert_eq!(evictions[0].reason, EvictionReason::Capacity);
+        assert_eq!(evictions[0].received, vec![0]);
+        assert!(!evictions[0].last_received);
+        Ok(())
+    }
+
+    #[test]
+    fn test_complete_not_evicted() -> Result<()> {
+        let (message_buffer_cache, evictions) = recording_cache(2);
+        let mut message_buffer_cache = message_buffer_cache.with_memory_budget(40);
+        // Complete, but not taken yet
+        message_buffer_cache.add(MessageChunk::from("BAZ222222222222YPMRGM33PEI5.foo.co", &"foo.co".into())?)?;
+        message_buffer_cache.add(MessageChunk::from("AA33333333333333FOO.foo.co", &"foo.co".into())?)?;
+        message_buffer_cache.add(MessageChunk::from("AA44444444444444FOO.foo.co", &"foo.co".into())?)?;
+        assert!(message_buffer_cache.message_buffers.contains(&key("Z222222222222")));
+        assert_eq!(evictions.lock().unwrap().iter().map(|evicted| evicted.id.as_str()).collect::<Vec<_>>(), vec!["3333333333333"]);
+
+        // With nothing partial left to drop, new messages are turned away
+        message_buffer_cache.add(MessageChunk::from("BB44444444444444FOO.foo.co", &"foo.co".into())?)?;
+        assert!(message_buffer_cache.add(MessageChunk::from("AA55555555555555FOO.foo.co", &"foo.co".into())?).is_err());
+        assert_eq!(message_buffer_cache.pending_completed().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_ttl() -> Result<()> {
+        let (mut message_buffer_cache, evictions) = recording_cache(3);
+        message_buffer_cache.add(MessageChunk::from("AA22222222222222FOO.foo.co", &"foo.co".into())?)?;
+        message_buffer_cache.add(MessageChunk::from("BC2222222222222ZFOO.foo.co", &"foo.co".into())?)?;
+        message_buffer_cache.expire();
+        assert_eq!(message_buffer_cache.message_buffers.len(), 1);
+
+        message_buffer_cache = message_buffer_cache.with_message_ttl(Duration::from_secs(0));
+        message_buffer_cache.expire();
+        assert_eq!(message_buffer_cache.message_buffers.len(), 0);
+        assert_eq!(message_buffer_cache.memory_used, 0);
+
+        let evictions = evictions.lock().unwrap();
+        assert_eq!(evictions.len(), 1);
+        assert_eq!(evictions[0].reason, EvictionReason::Expired);
+        assert_eq!(evictions[0].received, vec![0, 2]);
+        assert_eq!(evictions[0].missing, vec![1]);
+        assert!(evictions[0].last_received);
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_budget() -> Result<()> {
+        let (message_buffer_cache, evictions) = recording_cache(10);
+        let mut message_buffer_cache = message_buffer_cache.with_memory_budget(40);
+        message_buffer_cache.add(MessageChunk::from("AA22222222222222FOO.foo.co", &"foo.co".into())?)?;
+        message_buffer_cache.add(MessageChunk::from("AA33333333333333FOO.foo.co", &"foo.co".into())?)?;
+        message_buffer_cache.add(MessageChunk::from("AA44444444444444FOO.foo.co", &"foo.co".into())?)?;
+        assert_eq!(message_buffer_cache.message_buffers.len(), 2);
+        assert_eq!(message_buffer_cache.memory_used, 38);
+        assert!(!message_buffer_cache.message_buffers.contains(&key("2222222222222")));
+        assert_eq!(evictions.lock().unwrap()[0].reason, EvictionReason::MemoryBudget);
+        Ok(())
+    }
+}
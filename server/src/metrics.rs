@@ -0,0 +1,221 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::dns::Transport;
+use crate::geo_cache::GeoCacheStats;
+use crate::message_handler::EvictionReason;
+
+// Messages are at most 32 chunks, the index being one base32 character
+const CHUNKS_PER_MESSAGE_BUCKETS: &[f64] = &[1.0, 2.0, 3.0, 4.0, 6.0, 8.0, 12.0, 16.0, 24.0, 32.0];
+const REASSEMBLY_SECONDS_BUCKETS: &[f64] = &[0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    // DnsPacket::from_buffer couldn't make sense of a packet
+    DnsPacket,
+    // MessageChunk::from rejected a name with a chunk header
+    MessageChunk,
+}
+
+#[derive(Debug)]
+struct HistogramCounts {
+    // Not cumulative, those are summed up when rendered
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Mutex<HistogramCounts>,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        let counts = HistogramCounts { buckets: vec![0; bounds.len()], sum: 0.0, count: 0 };
+        Histogram { bounds, counts: Mutex::new(counts) }
+    }
+
+    fn observe(&self, value: f64) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            counts.buckets[bucket] += 1;
+        }
+        counts.sum += value;
+        counts.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        let counts = self.counts.lock().unwrap();
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&counts.buckets) {
+            cumulative += count;
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative).unwrap();
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, counts.count).unwrap();
+        writeln!(out, "{}_sum {}", name, counts.sum).unwrap();
+        writeln!(out, "{}_count {}", name, counts.count).unwrap();
+    }
+}
+
+/// Counters for the tunnel, shared by every thread and rendered in the
+/// Prometheus text format on /metrics
+#[derive(Debug)]
+pub struct Metrics {
+    queries: [AtomicU64; 2],
+    parse_errors: [AtomicU64; 2],
+    // Queries for names above a chunk, sent by resolvers doing QNAME minimisation
+    minimised_queries: AtomicU64,
+    completions: AtomicU64,
+    evictions: [AtomicU64; 3],
+    chunks_per_message: Histogram,
+    reassembly_seconds: Histogram,
+    cache_messages: AtomicUsize,
+    cache_memory: AtomicUsize,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            queries: Default::default(),
+            parse_errors: Default::default(),
+            minimised_queries: AtomicU64::new(0),
+            completions: AtomicU64::new(0),
+            evictions: Default::default(),
+            chunks_per_message: Histogram::new(CHUNKS_PER_MESSAGE_BUCKETS),
+            reassembly_seconds: Histogram::new(REASSEMBLY_SECONDS_BUCKETS),
+            cache_messages: AtomicUsize::new(0),
+            cache_memory: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn query_received(&self, transport: Transport) {
+        self.queries[transport as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn parse_error(&self, kind: ParseError) {
+        self.parse_errors[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn minimised_query(&self) {
+        self.minimised_queries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A message delivered, `reassembly` after its first chunk arrived
+    pub fn message_completed(&self, chunks: usize, reassembly: Duration) {
+        self.completions.fetch_add(1, Ordering::Relaxed);
+        self.chunks_per_message.observe(chunks as f64);
+        self.reassembly_seconds.observe(reassembly.as_secs_f64());
+    }
+
+    pub fn message_evicted(&self, reason: EvictionReason) {
+        self.evictions[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// What the message cache holds, set whenever it changes
+    pub fn set_cache_occupancy(&self, messages: usize, memory: usize) {
+        self.cache_messages.store(messages, Ordering::Relaxed);
+        self.cache_memory.store(memory, Ordering::Relaxed);
+    }
+
+    /// Everything in the Prometheus text exposition format, along with the
+    /// geolocation cache if there is one
+    pub fn render(&self, geo_cache: Option<GeoCacheStats>) -> String {
+        let mut out = String::new();
+        family(&mut out, "dns_drop_queries_total", "counter", "DNS queries received");
+        for (transport, label) in [(Transport::Udp, "udp"), (Transport::Tcp, "tcp")] {
+            let count = self.queries[transport as usize].load(Ordering::Relaxed);
+            writeln!(out, "dns_drop_queries_total{{transport=\"{}\"}} {}", label, count).unwrap();
+        }
+        family(&mut out, "dns_drop_parse_errors_total", "counter", "Queries which couldn't be parsed, by what failed");
+        for (kind, label) in [(ParseError::DnsPacket, "dns_packet"), (ParseError::MessageChunk, "message_chunk")] {
+            let count = self.parse_errors[kind as usize].load(Ordering::Relaxed);
+            writeln!(out, "dns_drop_parse_errors_total{{kind=\"{}\"}} {}", label, count).unwrap();
+        }
+        family(&mut out, "dns_drop_minimised_queries_total", "counter", "Queries for names above a chunk, from resolvers doing QNAME minimisation");
+        writeln!(out, "dns_drop_minimised_queries_total {}", self.minimised_queries.load(Ordering::Relaxed)).unwrap();
+        family(&mut out, "dns_drop_messages_completed_total", "counter", "Messages reassembled and delivered");
+        writeln!(out, "dns_drop_messages_completed_total {}", self.completions.load(Ordering::Relaxed)).unwrap();
+        family(&mut out, "dns_drop_messages_evicted_total", "counter", "Messages dropped from the cache before they were delivered, by why");
+        for (reason, label) in [
+            (EvictionReason::Capacity, "capacity"),
+            (EvictionReason::Expired, "expired"),
+            (EvictionReason::MemoryBudget, "memory_budget"),
+        ] {
+            let count = self.evictions[reason as usize].load(Ordering::Relaxed);
+            writeln!(out, "dns_drop_messages_evicted_total{{reason=\"{}\"}} {}", label, count).unwrap();
+        }
+        family(&mut out, "dns_drop_chunks_per_message", "histogram", "Chunks making up each completed message");
+        self.chunks_per_message.render(&mut out, "dns_drop_chunks_per_message");
+        family(&mut out, "dns_drop_reassembly_seconds", "histogram", "Time from the first to the last chunk of each completed message");
+        self.reassembly_seconds.render(&mut out, "dns_drop_reassembly_seconds");
+        family(&mut out, "dns_drop_cache_messages", "gauge", "Incomplete messages held in the cache");
+        writeln!(out, "dns_drop_cache_messages {}", self.cache_messages.load(Ordering::Relaxed)).unwrap();
+        family(&mut out, "dns_drop_cache_memory_bytes", "gauge", "Memory taken up by the chunks of incomplete messages");
+        writeln!(out, "dns_drop_cache_memory_bytes {}", self.cache_memory.load(Ordering::Relaxed)).unwrap();
+
+        if let Some(stats) = geo_cache {
+            family(&mut out, "dns_drop_geo_cache_lookups_total", "counter", "Geolocation cache lookups, by outcome");
+            writeln!(out, "dns_drop_geo_cache_lookups_total{{result=\"hit\"}} {}", stats.hits).unwrap();
+            writeln!(out, "dns_drop_geo_cache_lookups_total{{result=\"miss\"}} {}", stats.misses).unwrap();
+            family(&mut out, "dns_drop_geo_cache_entries", "gauge", "Fixes held in the geolocation cache");
+            writeln!(out, "dns_drop_geo_cache_entries {}", stats.entries).unwrap();
+        }
+        out
+    }
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.query_received(Transport::Udp);
+        metrics.query_received(Transport::Udp);
+        metrics.query_received(Transport::Tcp);
+        metrics.parse_error(ParseError::MessageChunk);
+        metrics.minimised_query();
+        metrics.message_completed(4, Duration::from_millis(1500));
+        metrics.message_completed(1, Duration::ZERO);
+        metrics.message_evicted(EvictionReason::Expired);
+        metrics.set_cache_occupancy(3, 420);
+
+        let rendered = metrics.render(None);
+        for line in [
+            "# TYPE dns_drop_queries_total counter",
+            "dns_drop_queries_total{transport=\"udp\"} 2",
+            "dns_drop_queries_total{transport=\"tcp\"} 1",
+            "dns_drop_parse_errors_total{kind=\"dns_packet\"} 0",
+            "dns_drop_parse_errors_total{kind=\"message_chunk\"} 1",
+            "dns_drop_minimised_queries_total 1",
+            "dns_drop_messages_completed_total 2",
+            "dns_drop_messages_evicted_total{reason=\"expired\"} 1",
+            // Buckets count everything at or below them
+            "dns_drop_chunks_per_message_bucket{le=\"1\"} 1",
+            "dns_drop_chunks_per_message_bucket{le=\"3\"} 1",
+            "dns_drop_chunks_per_message_bucket{le=\"4\"} 2",
+            "dns_drop_chunks_per_message_bucket{le=\"+Inf\"} 2",
+            "dns_drop_chunks_per_message_sum 5",
+            "dns_drop_reassembly_seconds_bucket{le=\"1\"} 1",
+            "dns_drop_reassembly_seconds_bucket{le=\"2\"} 2",
+            "dns_drop_reassembly_seconds_sum 1.5",
+            "dns_drop_cache_messages 3",
+            "dns_drop_cache_memory_bytes 420",
+        ] {
+            assert!(rendered.lines().any(|rendered| rendered == line), "missing {}", line);
+        }
+        assert!(!rendered.contains("geo_cache"));
+
+        let rendered = metrics.render(Some(GeoCacheStats { hits: 5, misses: 2, entries: 2 }));
+        assert!(rendered.contains("dns_drop_geo_cache_lookups_total{result=\"hit\"} 5\n"));
+    }
+}
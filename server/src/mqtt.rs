@@ -0,0 +1,449 @@
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::events::{MessageEvent, MESSAGE_COMPLETED, MESSAGE_LOCATED};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+pub const TOPIC_PREFIX: &str = "dns_drop";
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+const DEFAULT_CLIENT_ID: &str = "dns_drop";
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+// Home Assistant treats any other state as the name of a zone
+const NOT_HOME: &str = "not_home";
+
+// MQTT 3.1.1 control packet types, in the upper nibble of the first byte
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const PUBREC: u8 = 0x50;
+// PUBREL has its reserved flags set to 0010 (MQTT 3.1.1 2.2.2)
+const PUBREL: u8 = 0x62;
+const PUBCOMP: u8 = 0x70;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)] // The names the MQTT spec gives them
+pub enum QoS {
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+    ExactlyOnce = 2,
+}
+
+impl QoS {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "0" => Some(QoS::AtMostOnce),
+            "1" => Some(QoS::AtLeastOnce),
+            "2" => Some(QoS::ExactlyOnce),
+            _ => None,
+        }
+    }
+}
+
+// Seven bits at a time, least significant first, the top bit set on all but
+// the last byte (MQTT 3.1.1 2.2.3)
+fn encode_remaining_length(mut length: usize, packet: &mut Vec<u8>) {
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            return;
+        }
+    }
+}
+
+fn encode_string(text: &str, packet: &mut Vec<u8>) {
+    packet.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    packet.extend_from_slice(text.as_bytes());
+}
+
+fn packet(first_byte: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![first_byte];
+    encode_remaining_length(body.len(), &mut packet);
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Read a whole control packet, returning its first byte and the rest
+fn read_packet(stream: &mut impl Read) -> Result<(u8, Vec<u8>)> {
+    let mut first_byte = [0; 1];
+    stream.read_exact(&mut first_byte)?;
+    let mut length = 0;
+    for shift in (0..4).map(|i| i * 7) {
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte)?;
+        length |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body)?;
+            return Ok((first_byte[0], body));
+        }
+    }
+    Err("Malformed MQTT remaining length".into())
+}
+
+/// A connection to an MQTT 3.1.1 broker, only ever publishing
+pub struct MqttClient {
+    stream: TcpStream,
+    next_packet_id: u16,
+}
+
+impl MqttClient {
+    pub fn connect(address: &str, client_id: &str, credentials: Option<(&str, &str)>) -> Result<Self> {
+        let stream = TcpStream::connect(address)
+            .map_err(|e| format!("Unable to connect to MQTT broker {}: {}", address, e))?;
+        stream.set_read_timeout(Some(ACK_TIMEOUT))?;
+
+        let mut body = Vec::new();
+        encode_string("MQTT", &mut body);
+        // Protocol level 4 is 3.1.1
+        body.push(4);
+        // Clean session, plus user name and password if given
+        body.push(if credentials.is_some() { 0xc2 } else { 0x02 });
+        body.extend_from_slice(&(KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+        encode_string(client_id, &mut body);
+        if let Some((username, password)) = credentials {
+            encode_string(username, &mut body);
+            encode_string(password, &mut body);
+        }
+        let mut client = MqttClient { stream, next_packet_id: 1 };
+        client.stream.write_all(&packet(CONNECT, &body))?;
+
+        let (first_byte, body) = read_packet(&mut client.stream)?;
+        if first_byte != CONNACK || body.len() != 2 {
+            return Err(format!("Expected CONNACK from {}, got packet type {:#x}", address, first_byte).into());
+        }
+        let refused = match body[1] {
+            0 => return Ok(client),
+            1 => "unacceptable protocol version",
+            2 => "client identifier rejected",
+            3 => "server unavailable",
+            4 => "bad user name or password",
+            5 => "not authorized",
+            _ => "unknown return code",
+        };
+        Err(format!("MQTT broker {} refused the connection: {} ({})", address, refused, body[1]).into())
+    }
+
+    /// Publish and, above QoS 0, wait for the broker to acknowledge it
+    pub fn publish(&mut self, topic: &str, payload: &[u8], qos: QoS, retain: bool) -> Result<()> {
+        let packet_id = self.next_packet_id;
+        // 0 isn't a valid packet identifier
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+
+        let mut body = Vec::with_capacity(topic.len() + payload.len() + 4);
+        encode_string(topic, &mut body);
+        if qos != QoS::AtMostOnce {
+            body.extend_from_slice(&packet_id.to_be_bytes());
+        }
+        body.extend_from_slice(payload);
+        self.stream.write_all(&packet(PUBLISH | (qos as u8) << 1 | retain as u8, &body))?;
+
+        match qos {
+            QoS::AtMostOnce => Ok(()),
+            QoS::AtLeastOnce => self.wait_for(PUBACK, Some(packet_id)),
+            QoS::ExactlyOnce => {
+                self.wait_for(PUBREC, Some(packet_id))?;
+                self.stream.write_all(&packet(PUBREL, &packet_id.to_be_bytes()))?;
+                self.wait_for(PUBCOMP, Some(packet_id))
+            }
+        }
+    }
+
+    /// Let the broker know we're still here when there's nothing to publish
+    pub fn ping(&mut self) -> Result<()> {
+        self.stream.write_all(&packet(PINGREQ, &[]))?;
+        self.wait_for(PINGRESP, None)
+    }
+
+    // Nothing else a publisher is sent needs handling, anything else is skipped
+    fn wait_for(&mut self, packet_type: u8, packet_id: Option<u16>) -> Result<()> {
+        loop {
+            let (first_byte, body) = read_packet(&mut self.stream)?;
+            let id_matches = match packet_id {
+                Some(packet_id) => body.get(..2) == Some(&packet_id.to_be_bytes()[..]),
+                None => true,
+            };
+            if first_byte & 0xf0 == packet_type && id_matches {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Publishes events under `dns_drop/ZONE/DEVICE/`: each completed message to
+/// `message`, and each fix, retained, to `location` along with the place it
+/// was recognised as, or not_home, to `state`. Devices are announced to Home
+/// Assistant as device trackers the first time they're seen on a connection.
+pub struct MqttPublisher {
+    address: String,
+    client_id: String,
+    credentials: Option<(String, String)>,
+    qos: QoS,
+    discovery_prefix: Option<String>,
+    client: Option<MqttClient>,
+    announced: HashSet<String>,
+}
+
+impl MqttPublisher {
+    pub fn new(address: &str) -> Self {
+        MqttPublisher {
+            address: address.to_string(),
+            client_id: DEFAULT_CLIENT_ID.to_string(),
+            credentials: None,
+            qos: QoS::AtLeastOnce,
+            discovery_prefix: Some(DEFAULT_DISCOVERY_PREFIX.to_string()),
+            client: None,
+            announced: HashSet::new(),
+        }
+    }
+
+    pub fn with_client_id(mut self, client_id: &str) -> Self {
+        self.client_id = client_id.to_string();
+        self
+    }
+
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some((username.to_string(), password.to_string()));
+        self
+    }
+
+    pub fn with_qos(mut self, qos: QoS) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    /// Where Home Assistant looks for discovery payloads, None to not announce devices
+    pub fn with_discovery_prefix(mut self, discovery_prefix: Option<&str>) -> Self {
+        self.discovery_prefix = discovery_prefix.map(str::to_string);
+        self
+    }
+
+    pub fn publish(&mut self, event: &MessageEvent) -> Result<()> {
+        let was_connected = self.client.is_some();
+        let mut result = self.try_publish(event);
+        // The broker may have dropped a connection which sat idle, worth
+        // one more go on a fresh one
+        if result.is_err() && was_connected {
+            self.client = None;
+            result = self.try_publish(event);
+        }
+        if result.is_err() {
+            self.client = None;
+        }
+        result
+    }
+
+    fn try_publish(&mut self, event: &MessageEvent) -> Result<()> {
+        if self.client.is_none() {
+            let credentials = self.credentials.as_ref().map(|(username, password)| (username.as_str(), password.as_str()));
+            self.client = Some(MqttClient::connect(&self.address, &self.client_id, credentials)?);
+            // Retained discovery payloads may have been cleared while we were away
+            self.announced.clear();
+            println!("Connected to MQTT broker {}", self.address);
+        }
+        let client = self.client.as_mut().unwrap();
+        let topic = format!("{}/{}/{}", TOPIC_PREFIX, event.zone, event.device_id);
+
+        if let Some(discovery_prefix) = &self.discovery_prefix {
+            if !self.announced.contains(&topic) {
+                let unique_id = format!("{}_{}", TOPIC_PREFIX, event.device_id);
+                let config = json!({
+                    "name": event.device_id,
+                    "unique_id": unique_id,
+                    "state_topic": format!("{}/state", topic),
+                    "json_attributes_topic": format!("{}/location", topic),
+                    "source_type": "gps",
+                    "device": {"identifiers": [unique_id], "name": event.device_id, "manufacturer": "dns_drop"},
+                });
+                let config_topic = format!("{}/device_tracker/{}/config", discovery_prefix, unique_id);
+                client.publish(&config_topic, config.to_string().as_bytes(), self.qos, true)?;
+                self.announced.insert(topic.clone());
+            }
+        }
+
+        match (event.event, &event.location) {
+            (MESSAGE_COMPLETED, _) => {
+                client.publish(&format!("{}/message", topic), serde_json::to_string(event)?.as_bytes(), self.qos, false)?;
+            }
+            (MESSAGE_LOCATED, Some(location)) => {
+                // Named the way Home Assistant reads a device tracker's position
+                let attributes = json!({
+                    "latitude": location.lat,
+                    "longitude": location.lng,
+                    "gps_accuracy": location.accuracy,
+                    "time": event.last_chunk_at,
+                    "message_id": event.message_id,
+                    "place": event.place.as_ref().map(|place| &place.name),
+                });
+                client.publish(&format!("{}/location", topic), attributes.to_string().as_bytes(), self.qos, true)?;
+                let state = event.place.as_ref().map_or(NOT_HOME, |place| place.name.as_str());
+                client.publish(&format!("{}/state", topic), state.as_bytes(), self.qos, true)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Ping the broker if connected, dropping the connection if it's gone
+    pub fn keep_alive(&mut self) {
+        if let Some(client) = &mut self.client {
+            if let Err(e) = client.ping() {
+                eprintln!("Lost MQTT broker {}: {}", self.address, e);
+                self.client = None;
+            }
+        }
+    }
+}
+
+/// Publish events as they come in, keeping the connection alive in between
+pub fn publish_events(mut publisher: MqttPublisher, events: Receiver<MessageEvent>) {
+    loop {
+        match events.recv_timeout(KEEP_ALIVE / 2) {
+            Ok(event) => {
+                if let Err(e) = publisher.publish(&event) {
+                    eprintln!("[{}] Unable to publish {} of {} to MQTT: {}", event.zone, event.event, event.message_id, e);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => publisher.keep_alive(),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geolocation::Location;
+    use crate::places::PlaceMatch;
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+
+    #[derive(Debug, PartialEq)]
+    struct Published {
+        topic: String,
+        qos: u8,
+        retain: bool,
+        payload: String,
+    }
+
+    /// A broker taking one connection, answering CONNECT with `return_code`
+    /// and acknowledging everything published, which it passes on
+    fn broker(return_code: u8) -> (String, Receiver<Published>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            while let Ok((first_byte, body)) = read_packet(&mut stream) {
+                match first_byte & 0xf0 {
+                    CONNECT => stream.write_all(&packet(CONNACK, &[0, return_code])).unwrap(),
+                    PUBLISH => {
+                        let qos = (first_byte >> 1) & 0x03;
+                        let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                        let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+                        let mut payload = &body[2 + topic_len..];
+                        if qos > 0 {
+                            let ack = if qos == 1 { PUBACK } else { PUBREC };
+                            stream.write_all(&packet(ack, &payload[..2])).unwrap();
+                            payload = &payload[2..];
+                        }
+                        let payload = String::from_utf8(payload.to_vec()).unwrap();
+                        sender.send(Published { topic, qos, retain: first_byte & 0x01 == 1, payload }).unwrap();
+                    }
+                    0x60 => stream.write_all(&packet(PUBCOMP, &body)).unwrap(),
+                    PINGREQ => stream.write_all(&packet(PINGRESP, &[])).unwrap(),
+                    _ => {}
+                }
+            }
+        });
+        (address, receiver)
+    }
+
+    fn event(event: &'static str) -> MessageEvent {
+        MessageEvent {
+            event,
+            device_id: "ZACKAAAA".to_string(),
+            message_id: "ZACKAAAAQX2MD".to_string(),
+            zone: "i.mdp.im".to_string(),
+            first_chunk_at: "2020-09-13T12:26:40Z".to_string(),
+            last_chunk_at: "2020-09-13T12:26:43Z".to_string(),
+            payload: json!({"ssid": "Cafe"}),
+            place: Some(PlaceMatch { name: "depot 3".to_string(), confidence: 0.8 }),
+            location: Some(Location { lat: 51.5, lng: -0.12, accuracy: 20.0, ellipse: None }),
+        }
+    }
+
+    #[test]
+    fn test_remaining_length() -> Result<()> {
+        for (length, encoded) in [
+            (0, vec![0x00]),
+            (127, vec![0x7f]),
+            (128, vec![0x80, 0x01]),
+            (16_383, vec![0xff, 0x7f]),
+            (16_384, vec![0x80, 0x80, 0x01]),
+        ] {
+            let mut packet = Vec::new();
+            encode_remaining_length(length, &mut packet);
+            assert_eq!(packet, encoded);
+            let (_, body) = read_packet(&mut &super::packet(PUBLISH, &vec![0; length])[..])?;
+            assert_eq!(body.len(), length);
+        }
+        assert!(read_packet(&mut &[PUBLISH, 0xff, 0xff, 0xff, 0xff, 0x01][..]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_publish() -> Result<()> {
+        let (address, published) = broker(0);
+        let mut publisher = MqttPublisher::new(&address).with_qos(QoS::ExactlyOnce);
+        publisher.publish(&event(MESSAGE_COMPLETED))?;
+        publisher.publish(&event(MESSAGE_LOCATED))?;
+        publisher.keep_alive();
+        assert!(publisher.client.is_some());
+
+        let discovery = published.recv()?;
+        assert_eq!((discovery.topic.as_str(), discovery.retain), ("homeassistant/device_tracker/dns_drop_ZACKAAAA/config", true));
+        let config: serde_json::Value = serde_json::from_str(&discovery.payload)?;
+        assert_eq!(config["json_attributes_topic"], "dns_drop/i.mdp.im/ZACKAAAA/location");
+        let message = published.recv()?;
+        assert_eq!((message.topic.as_str(), message.qos, message.retain), ("dns_drop/i.mdp.im/ZACKAAAA/message", 2, false));
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&message.payload)?["payload"]["ssid"], "Cafe");
+        // Announced once per connection
+        let location = published.recv()?;
+        assert_eq!((location.topic.as_str(), location.retain), ("dns_drop/i.mdp.im/ZACKAAAA/location", true));
+        let attributes: serde_json::Value = serde_json::from_str(&location.payload)?;
+        assert_eq!((attributes["latitude"].as_f64(), attributes["gps_accuracy"].as_f64()), (Some(51.5), Some(20.0)));
+        assert_eq!(published.recv()?, Published {
+            topic: "dns_drop/i.mdp.im/ZACKAAAA/state".to_string(),
+            qos: 2,
+            retain: true,
+            payload: "depot 3".to_string(),
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn test_refused() {
+        let (address, _published) = broker(4);
+        let mut publisher = MqttPublisher::new(&address).with_credentials("dns_drop", "wrong");
+        let error = publisher.publish(&event(MESSAGE_COMPLETED)).unwrap_err();
+        assert!(error.to_string().contains("bad user name or password"));
+        assert!(publisher.client.is_none());
+    }
+}
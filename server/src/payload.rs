@@ -0,0 +1,170 @@
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+// The only report format devices send so far, see esp32tracker.ino
+pub const WIFI_SCAN_VERSION: u8 = 0;
+// [MAC 6][channel 1][RSSI 1]
+const ACCESS_POINT_LEN: usize = 8;
+// 802.11 caps SSIDs at 32 bytes
+const MAX_SSID_LEN: usize = 32;
+
+/// A BSSID, printed as colon separated uppercase hex
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    /// Set for addresses made up rather than assigned by the vendor, which
+    /// covers randomised BSSIDs and extra networks on one radio
+    pub fn is_locally_administered(&self) -> bool {
+        self.0[0] & 0x02 != 0
+    }
+
+    /// Accepts aa:bb:cc:dd:ee:ff, aa-bb-cc-dd-ee-ff or aabbccddeeff in any case
+    pub fn parse(text: &str) -> Option<Self> {
+        let hex: String = text.chars().filter(|c| *c != ':' && *c != '-').collect();
+        if hex.len() != 12 {
+            return None;
+        }
+        let mut mac = [0; 6];
+        for (i, octet) in mac.iter_mut().enumerate() {
+            *octet = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+        }
+        Some(MacAddress(mac))
+    }
+
+    /// The vendor's Organizationally Unique Identifier
+    pub fn oui(&self) -> [u8; 3] {
+        [self.0[0], self.0[1], self.0[2]]
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let octets: Vec<String> = self.0.iter().map(|octet| format!("{:02X}", octet)).collect();
+        write!(f, "{}", octets.join(":"))
+    }
+}
+
+impl Serialize for MacAddress {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MacAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        MacAddress::parse(&text).ok_or_else(|| serde::de::Error::custom(format!("Invalid MAC address {}", text)))
+    }
+}
+
+/// One network seen in a scan. Serialised the way the Google Geolocation
+/// API expects its `wifiAccessPoints`, see geo_sample.json
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessPoint {
+    pub mac_address: MacAddress,
+    pub channel: u8,
+    #[serde(rename = "signalStrength")]
+    pub rssi: i8,
+}
+
+/// A version 0 report: the networks a device could see, strongest first,
+/// and the open network it connected through to send them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WifiScan {
+    pub version: u8,
+    #[serde(rename = "wifiAccessPoints")]
+    pub access_points: Vec<AccessPoint>,
+    pub ssid: String,
+}
+
+impl WifiScan {
+    /// Parse a completed binary message:
+    /// `[version 1][AP count 1]([MAC 6][channel 1][RSSI 1])*[SSID]`
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let (&version, rest) = data.split_first().ok_or("Empty payload")?;
+        if version != WIFI_SCAN_VERSION {
+            return Err(format!("Unknown payload version {}", version).into());
+        }
+        let (&count, rest) = rest.split_first().ok_or("Payload is missing the access point count")?;
+
+        let aps_len = count as usize * ACCESS_POINT_LEN;
+        if rest.len() < aps_len {
+            return Err(format!(
+                "Payload holds {} bytes of access points, {} expected for {}",
+                rest.len(), aps_len, count
+            ).into());
+        }
+        let (aps, ssid) = rest.split_at(aps_len);
+        if ssid.len() > MAX_SSID_LEN {
+            return Err(format!("SSID is {} bytes long, at most {} expected", ssid.len(), MAX_SSID_LEN).into());
+        }
+
+        let access_points = aps
+            .chunks_exact(ACCESS_POINT_LEN)
+            .map(|ap| {
+                let mut mac = [0; 6];
+                mac.copy_from_slice(&ap[0..6]);
+                AccessPoint {
+                    mac_address: MacAddress(mac),
+                    channel: ap[6],
+                    rssi: ap[7] as i8,
+                }
+            })
+            .collect();
+
+        Ok(WifiScan {
+            version,
+            access_points,
+            // SSIDs are arbitrary bytes, though nearly always UTF-8
+            ssid: String::from_utf8_lossy(ssid).into_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_sample() -> Result<()> {
+        let scan = WifiScan::decode(include_bytes!("../sample_log.bin"))?;
+        assert_eq!(scan.version, 0);
+        assert_eq!(scan.access_points.len(), 10);
+        assert_eq!(scan.ssid, "Starbucks WiFi");
+        assert_eq!(
+            scan.access_points[0],
+            AccessPoint {
+                mac_address: MacAddress([0x00, 0x11, 0x32, 0x68, 0x43, 0xa2]),
+                channel: 8,
+                rssi: -73,
+            }
+        );
+        assert_eq!(scan.access_points[9].mac_address.to_string(), "3C:37:86:D0:A3:88");
+        assert_eq!(scan.access_points[9].rssi, -91);
+
+        let json = serde_json::to_value(&scan)?;
+        assert_eq!(
+            json["wifiAccessPoints"][0],
+            serde_json::json!({"macAddress": "00:11:32:68:43:A2", "channel": 8, "signalStrength": -73})
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(WifiScan::decode(&[]).is_err());
+        assert!(WifiScan::decode(&[1, 0]).is_err());
+        assert!(WifiScan::decode(&[0]).is_err());
+        // Two access points announced, one and a half sent
+        assert!(WifiScan::decode(&[0, 2, 1, 2, 3, 4, 5, 6, 1, 0xb7, 1, 2, 3, 4]).is_err());
+        assert!(WifiScan::decode(&[&[0, 0][..], &[b'a'; 33][..]].concat()).is_err());
+        assert_eq!(WifiScan::decode(&[0, 0]).unwrap().ssid, "");
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::payload::{AccessPoint, MacAddress, WifiScan};
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+// Weakest signal a radio picks up, APs are weighted by how far above it they are
+const NOISE_FLOOR_DBM: f64 = -100.0;
+pub const DEFAULT_THRESHOLD: f64 = 0.4;
+
+/// A named spot and the scans taken there to recognise it by
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Place {
+    pub name: String,
+    pub references: Vec<Vec<AccessPoint>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PlacesFile {
+    places: Vec<Place>,
+}
+
+/// The place a scan was recognised as, `confidence` being its similarity
+/// to the closest reference scan, from 0 to 1
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlaceMatch {
+    pub name: String,
+    pub confidence: f64,
+}
+
+/// Places enrolled from reference scans, see `dns_drop enrol`
+#[derive(Debug, Default)]
+pub struct Places {
+    places: Vec<Place>,
+    threshold: f64,
+}
+
+impl Places {
+    /// Load the places from `path`, a missing file has none enrolled
+    pub fn load(path: &Path) -> Result<Self> {
+        let places_file: PlacesFile = match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| format!("Invalid places {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => PlacesFile::default(),
+            Err(e) => return Err(format!("Unable to read places {}: {}", path.display(), e).into()),
+        };
+        Ok(Places { places: places_file.places, threshold: DEFAULT_THRESHOLD })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let places_file = PlacesFile { places: self.places.clone() };
+        // Written aside and moved into place, so a crash can't lose what was enrolled
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(&places_file)?)
+            .map_err(|e| format!("Unable to write to {}: {}", tmp_path.display(), e))?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Least similarity a scan needs to a reference to be recognised. Zero
+    /// would recognise every scan, above one none at all.
+    pub fn with_threshold(mut self, threshold: f64) -> Result<Self> {
+        if !(threshold > 0.0 && threshold <= 1.0) {
+            return Err(format!("Place threshold must be above 0 and at most 1, not {}", threshold).into());
+        }
+        self.threshold = threshold;
+        Ok(self)
+    }
+
+    pub fn len(&self) -> usize {
+        self.places.len()
+    }
+
+    /// Add `scan` as a reference for the place `name`, creating it if needed.
+    /// Several references taken around a place make it easier to recognise.
+    pub fn enrol(&mut self, name: &str, scan: &WifiScan) {
+        let reference = scan.access_points.clone();
+        match self.places.iter_mut().find(|place| place.name == name) {
+            Some(place) => place.references.push(reference),
+            None => self.places.push(Place { name: name.to_string(), references: vec![reference] }),
+        }
+    }
+
+    /// The enrolled place most like `scan`, if any is close enough
+    pub fn recognise(&self, scan: &WifiScan) -> Option<PlaceMatch> {
+        self.places.iter()
+            .flat_map(|place| place.references.iter().map(move |reference| (place, similarity(reference, &scan.access_points))))
+            .filter(|(_, confidence)| *confidence >= self.threshold)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(place, confidence)| PlaceMatch { name: place.name.clone(), confidence })
+    }
+}
+
+/// Weighted Jaccard index of two scans: APs seen in both count by their
+/// weaker signal, against the stronger signal of every AP seen in either.
+/// Gives 1 for the same APs at the same strengths.
+pub fn similarity(a: &[AccessPoint], b: &[AccessPoint]) -> f64 {
+    let (a, b) = (weights(a), weights(b));
+    let mut shared = 0.0;
+    let mut union = 0.0;
+    for (mac, weight_a) in &a {
+        let weight_b = b.get(mac).copied().unwrap_or(0.0);
+        shared += weight_a.min(weight_b);
+        union += weight_a.max(weight_b);
+    }
+    union += b.iter().filter(|(mac, _)| !a.contains_key(mac)).map(|(_, weight)| weight).sum::<f64>();
+    if union == 0.0 {
+        return 0.0;
+    }
+    shared / union
+}
+
+fn weights(access_points: &[AccessPoint]) -> HashMap<MacAddress, f64> {
+    let mut weights = HashMap::new();
+    for ap in access_points {
+        let weight = (ap.rssi as f64 - NOISE_FLOOR_DBM).max(0.0);
+        let entry = weights.entry(ap.mac_address).or_insert(0.0);
+        *entry = weight.max(*entry);
+    }
+    weights
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{scan, TempPath};
+
+    #[test]
+    fn test_similarity() {
+        let a = scan(&[(1, -50), (2, -70)]);
+        assert_eq!(similarity(&a.access_points, &a.access_points), 1.0);
+        // min(50, 40) + min(30, 30) over max(50, 40) + max(30, 30)
+        assert!((similarity(&a.access_points, &scan(&[(1, -60), (2, -70)]).access_points) - 70.0 / 80.0).abs() < 1e-9);
+        // An AP only one side heard counts fully against
+        assert!((similarity(&a.access_points, &scan(&[(1, -50), (3, -80)]).access_points) - 50.0 / 100.0).abs() < 1e-9);
+        assert_eq!(similarity(&a.access_points, &scan(&[(3, -50)]).access_points), 0.0);
+        assert_eq!(similarity(&[], &[]), 0.0);
+    }
+
+    #[test]
+    fn test_recognise() -> Result<()> {
+        let path = TempPath::new("places.json");
+        let mut places = Places::load(&path)?;
+        places.enrol("warehouse", &scan(&[(1, -50), (2, -60), (3, -70)]));
+        places.enrol("depot 3", &scan(&[(7, -55), (8, -65)]));
+        places.enrol("warehouse", &WifiScan::decode(include_bytes!("../sample_log.bin"))?);
+        places.save(&path)?;
+
+        let places = Places::load(&path)?;
+        assert_eq!(places.len(), 2);
+        let matched = places.recognise(&scan(&[(1, -52), (2, -61), (3, -75), (4, -90)])).unwrap();
+        assert_eq!(matched.name, "warehouse");
+        assert!(matched.confidence > 0.7 && matched.confidence < 1.0);
+        assert_eq!(places.recognise(&WifiScan::decode(include_bytes!("../sample_log.bin"))?).unwrap().confidence, 1.0);
+        assert_eq!(places.recognise(&scan(&[(7, -55), (9, -40)])), None);
+        assert!(Places::load(&path)?.with_threshold(0.0).is_err());
+        assert!(Places::load(&path)?.with_threshold(1.5).is_err());
+        assert_eq!(places.with_threshold(0.2)?.recognise(&scan(&[(7, -55), (9, -40)])).unwrap().name, "depot 3");
+        Ok(())
+    }
+}
@@ -0,0 +1,444 @@
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+
+use crate::geolocation::{ErrorEllipse, Location};
+use crate::message_handler::{boot_counter_of, device_id_of};
+use crate::payload::WifiScan;
+use crate::places::PlaceMatch;
+use crate::zone::DomainName;
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+// Applied in order, the database's user_version being how many have run.
+// Only ever add to the end, databases out there are at every version.
+const MIGRATIONS: &[&str] = &[
+    // 1: Completed messages, the scans they carried and where they were taken
+    "CREATE TABLE devices (
+        id TEXT PRIMARY KEY,
+        first_seen INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL
+    );
+    CREATE TABLE messages (
+        id INTEGER PRIMARY KEY,
+        message_id TEXT NOT NULL,
+        device_id TEXT NOT NULL REFERENCES devices (id),
+        boot_counter INTEGER,
+        zone TEXT NOT NULL,
+        resolver TEXT,
+        first_chunk_at INTEGER NOT NULL,
+        last_chunk_at INTEGER NOT NULL,
+        raw BLOB NOT NULL,
+        ssid TEXT,
+        place TEXT,
+        place_confidence REAL
+    );
+    CREATE INDEX messages_by_device ON messages (device_id, last_chunk_at);
+    CREATE TABLE access_points (
+        message INTEGER NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+        mac TEXT NOT NULL,
+        channel INTEGER NOT NULL,
+        rssi INTEGER NOT NULL
+    );
+    CREATE INDEX access_points_by_message ON access_points (message);
+    CREATE TABLE locations (
+        message INTEGER PRIMARY KEY REFERENCES messages (id) ON DELETE CASCADE,
+        lat REAL NOT NULL,
+        lng REAL NOT NULL,
+        accuracy REAL NOT NULL,
+        located_at INTEGER NOT NULL,
+        -- Only for fixes trilaterated from path loss
+        ellipse_semi_major REAL,
+        ellipse_semi_minor REAL,
+        ellipse_orientation REAL
+    );",
+];
+
+/// A completed message and everything known about it on arrival
+#[derive(Debug)]
+pub struct StoredMessage<'a> {
+    pub message_id: &'a str,
+    pub zone: &'a DomainName,
+    // The resolver which sent the chunk completing the message
+    pub resolver: Option<SocketAddr>,
+    pub first_chunk_at: SystemTime,
+    pub last_chunk_at: SystemTime,
+    pub raw: &'a [u8],
+    pub scan: Option<&'a WifiScan>,
+    pub place: Option<&'a PlaceMatch>,
+}
+
+/// A located message along with what it was located from
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredFix {
+    pub message_id: String,
+    pub device_id: String,
+    pub boot_counter: Option<u8>,
+    pub zone: String,
+    pub resolver: Option<String>,
+    // When the last chunk arrived
+    pub received_at: SystemTime,
+    pub ssid: Option<String>,
+    pub access_points: usize,
+    pub place: Option<String>,
+    pub location: Location,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceSummary {
+    pub id: String,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+    pub messages: usize,
+}
+
+/// A stored message as it arrived, with its fix if it has one
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageRecord {
+    pub message_id: String,
+    pub device_id: String,
+    pub boot_counter: Option<u8>,
+    pub zone: String,
+    pub resolver: Option<String>,
+    pub first_chunk_at: SystemTime,
+    pub last_chunk_at: SystemTime,
+    pub raw: Vec<u8>,
+    pub ssid: Option<String>,
+    pub place: Option<String>,
+    pub place_confidence: Option<f64>,
+    pub location: Option<Location>,
+}
+
+/// Completed messages and fixes kept in SQLite, by device
+pub struct Storage {
+    connection: Connection,
+}
+
+impl Storage {
+    /// Open or create the database at `path`, bringing its schema up to date
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)
+            .map_err(|e| format!("Unable to open database {}: {}", path.display(), e))?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+        // Lets the HTTP API read on its own connection while messages are
+        // being recorded, waiting out the odd lock rather than failing
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.busy_timeout(Duration::from_secs(5))?;
+        let mut storage = Storage { connection };
+        storage.migrate()?;
+        Ok(storage)
+    }
+
+    /// Open a database the server created, only to read from it. Nothing is
+    /// created or migrated, so a mistyped path is an error rather than an
+    /// empty database.
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|e| format!("Unable to open database {}: {}", path.display(), e))?;
+        connection.busy_timeout(Duration::from_secs(5))?;
+        let storage = Storage { connection };
+        let version = storage.schema_version()?;
+        if version != MIGRATIONS.len() {
+            return Err(format!("Database {} is at schema version {}, this server reads version {}", path.display(), version, MIGRATIONS.len()).into());
+        }
+        Ok(storage)
+    }
+
+    pub fn schema_version(&self) -> Result<usize> {
+        let version: i64 = self.connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        Ok(version as usize)
+    }
+
+    fn migrate(&mut self) -> Result<()> {
+        let version = self.schema_version()?;
+        if version > MIGRATIONS.len() {
+            return Err(format!("Database is at schema version {}, newer than this server knows ({})", version, MIGRATIONS.len()).into());
+        }
+        for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let transaction = self.connection.transaction()?;
+            transaction.execute_batch(migration)?;
+            transaction.pragma_update(None, "user_version", (applied + 1) as i64)?;
+            transaction.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Store a completed message, returning its row to attach a fix to later
+    pub fn record_message(&mut self, message: &StoredMessage) -> Result<i64> {
+        let device_id = device_id_of(message.message_id);
+        let first_chunk_at = unix_secs(message.first_chunk_at);
+        let last_chunk_at = unix_secs(message.last_chunk_at);
+
+        let transaction = self.connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO devices (id, first_seen, last_seen) VALUES (?1, ?2, ?3)
+             ON CONFLICT (id) DO UPDATE SET first_seen = MIN(first_seen, excluded.first_seen),
+                                            last_seen = MAX(last_seen, excluded.last_seen)",
+            params![device_id, first_chunk_at, last_chunk_at],
+        )?;
+        transaction.execute(
+            "INSERT INTO messages (message_id, device_id, boot_counter, zone, resolver, first_chunk_at,
+                                   last_chunk_at, raw, ssid, place, place_confidence)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                message.message_id,
+                device_id,
+                boot_counter_of(message.message_id),
+                message.zone.to_string(),
+                message.resolver.map(|resolver| resolver.to_string()),
+                first_chunk_at,
+                last_chunk_at,
+                message.raw,
+                message.scan.map(|scan| scan.ssid.as_str()),
+                message.place.map(|place| place.name.as_str()),
+                message.place.map(|place| place.confidence),
+            ],
+        )?;
+        let row = transaction.last_insert_rowid();
+        if let Some(scan) = message.scan {
+            let mut insert = transaction.prepare("INSERT INTO access_points (message, mac, channel, rssi) VALUES (?1, ?2, ?3, ?4)")?;
+            for ap in &scan.access_points {
+                insert.execute(params![row, ap.mac_address.to_string(), ap.channel, ap.rssi])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(row)
+    }
+
+    pub fn record_location(&mut self, message_row: i64, location: &Location) -> Result<()> {
+        let ellipse = location.ellipse.as_ref();
+        self.connection.execute(
+            "INSERT OR REPLACE INTO locations (message, lat, lng, accuracy, located_at,
+                                               ellipse_semi_major, ellipse_semi_minor, ellipse_orientation)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                message_row,
+                location.lat,
+                location.lng,
+                location.accuracy,
+                unix_secs(SystemTime::now()),
+                ellipse.map(|ellipse| ellipse.semi_major),
+                ellipse.map(|ellipse| ellipse.semi_minor),
+                ellipse.map(|ellipse| ellipse.orientation),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Fixes of the messages a device sent between `from` and `to`, inclusive
+    /// and either open ended, oldest first
+    pub fn fixes(&self, device_id: &str, from: Option<SystemTime>, to: Option<SystemTime>) -> Result<Vec<StoredFix>> {
+        let mut select = self.connection.prepare(&format!(
+            "{} WHERE m.device_id = ?1 AND m.last_chunk_at BETWEEN ?2 AND ?3 ORDER BY m.last_chunk_at, m.id",
+            SELECT_FIXES,
+        ))?;
+        let rows = select.query_map(
+            params![device_id, from.map_or(0, unix_secs), to.map_or(i64::MAX, unix_secs)],
+            fix_from_row,
+        )?;
+        Ok(rows.collect::<std::result::Result<_, _>>()?)
+    }
+
+    pub fn latest_fix(&self, device_id: &str) -> Result<Option<StoredFix>> {
+        let fix = self.connection.query_row(
+            &format!("{} WHERE m.device_id = ?1 ORDER BY m.last_chunk_at DESC, m.id DESC LIMIT 1", SELECT_FIXES),
+            [device_id],
+            fix_from_row,
+        ).optional()?;
+        Ok(fix)
+    }
+
+    /// Every device heard from, most recently seen first
+    pub fn devices(&self) -> Result<Vec<DeviceSummary>> {
+        let mut select = self.connection.prepare(
+            "SELECT d.id, d.first_seen, d.last_seen, (SELECT COUNT(*) FROM messages m WHERE m.device_id = d.id)
+             FROM devices d ORDER BY d.last_seen DESC, d.id",
+        )?;
+        let rows = select.query_map([], |row| {
+            let messages: i64 = row.get(3)?;
+            Ok(DeviceSummary {
+                id: row.get(0)?,
+                first_seen: system_time(row.get(1)?),
+                last_seen: system_time(row.get(2)?),
+                messages: messages as usize,
+            })
+        })?;
+        Ok(rows.collect::<std::result::Result<_, _>>()?)
+    }
+
+    /// The latest message stored under `message_id`. IDs are only unique
+    /// among a device's recent messages, older ones may share it.
+    pub fn message(&self, message_id: &str) -> Result<Option<MessageRecord>> {
+        let message = self.connection.query_row(
+            "SELECT m.message_id, m.device_id, m.boot_counter, m.zone, m.resolver, m.first_chunk_at, m.last_chunk_at,
+                    m.raw, m.ssid, m.place, m.place_confidence, l.lat, l.lng, l.accuracy,
+                    l.ellipse_semi_major, l.ellipse_semi_minor, l.ellipse_orientation
+             FROM messages m LEFT JOIN locations l ON l.message = m.id
+             WHERE m.message_id = ?1 ORDER BY m.last_chunk_at DESC, m.id DESC LIMIT 1",
+            [message_id],
+            |row| {
+                let lat: Option<f64> = row.get(11)?;
+                let location = match lat {
+                    Some(lat) => Some(Location { lat, lng: row.get(12)?, accuracy: row.get(13)?, ellipse: ellipse_from_row(row, 14)? }),
+                    None => None,
+                };
+                Ok(MessageRecord {
+                    message_id: row.get(0)?,
+                    device_id: row.get(1)?,
+                    boot_counter: row.get(2)?,
+                    zone: row.get(3)?,
+                    resolver: row.get(4)?,
+                    first_chunk_at: system_time(row.get(5)?),
+                    last_chunk_at: system_time(row.get(6)?),
+                    raw: row.get(7)?,
+                    ssid: row.get(8)?,
+                    place: row.get(9)?,
+                    place_confidence: row.get(10)?,
+                    location,
+                })
+            },
+        ).optional()?;
+        Ok(message)
+    }
+}
+
+// Joined with the location so only located messages come back, see `fix_from_row`
+const SELECT_FIXES: &str =
+    "SELECT m.message_id, m.device_id, m.boot_counter, m.zone, m.resolver, m.last_chunk_at, m.ssid,
+            (SELECT COUNT(*) FROM access_points a WHERE a.message = m.id), m.place, l.lat, l.lng, l.accuracy,
+            l.ellipse_semi_major, l.ellipse_semi_minor, l.ellipse_orientation
+     FROM messages m JOIN locations l ON l.message = m.id";
+
+fn fix_from_row(row: &rusqlite::Row) -> rusqlite::Result<StoredFix> {
+    let access_points: i64 = row.get(7)?;
+    Ok(StoredFix {
+        message_id: row.get(0)?,
+        device_id: row.get(1)?,
+        boot_counter: row.get(2)?,
+        zone: row.get(3)?,
+        resolver: row.get(4)?,
+        received_at: system_time(row.get(5)?),
+        ssid: row.get(6)?,
+        access_points: access_points as usize,
+        place: row.get(8)?,
+        location: Location { lat: row.get(9)?, lng: row.get(10)?, accuracy: row.get(11)?, ellipse: ellipse_from_row(row, 12)? },
+    })
+}
+
+// The three ellipse columns starting at `first`, all NULL when there's none
+fn ellipse_from_row(row: &rusqlite::Row, first: usize) -> rusqlite::Result<Option<ErrorEllipse>> {
+    let semi_major: Option<f64> = row.get(first)?;
+    match semi_major {
+        Some(semi_major) => Ok(Some(ErrorEllipse { semi_major, semi_minor: row.get(first + 1)?, orientation: row.get(first + 2)? })),
+        None => Ok(None),
+    }
+}
+
+fn system_time(unix_secs: i64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(unix_secs.max(0) as u64)
+}
+
+fn unix_secs(at: SystemTime) -> i64 {
+    at.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempPath;
+
+    #[test]
+    fn test_migrations() -> Result<()> {
+        let path = TempPath::new("storage.sqlite");
+        assert_eq!(Storage::open(&path)?.schema_version()?, MIGRATIONS.len());
+        // Reopening leaves an up to date database alone
+        assert_eq!(Storage::open(&path)?.schema_version()?, MIGRATIONS.len());
+
+        Connection::open(&path)?.pragma_update(None, "user_version", MIGRATIONS.len() as i64 + 1)?;
+        assert!(Storage::open(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_read_only() -> Result<()> {
+        let path = TempPath::new("storage_read_only.sqlite");
+        assert!(Storage::open_read_only(&path).is_err());
+        assert!(!path.exists());
+
+        drop(Storage::open(&path)?);
+        let storage = Storage::open_read_only(&path)?;
+        assert!(storage.devices()?.is_empty());
+        assert!(storage.connection.execute("INSERT INTO devices (id, first_seen, last_seen) VALUES ('ZACKAAAA', 0, 0)", []).is_err());
+        drop(storage);
+
+        // Never migrated as a side effect
+        Connection::open(&path)?.pragma_update(None, "user_version", 0)?;
+        assert!(Storage::open_read_only(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_message() -> Result<()> {
+        let mut storage = Storage::open(Path::new(":memory:"))?;
+        let raw = include_bytes!("../sample_log.bin");
+        let scan = WifiScan::decode(raw)?;
+        let zone = DomainName::new("i.mdp.im");
+        let at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let place = PlaceMatch { name: "depot 3".to_string(), confidence: 0.8 };
+        let mut message = StoredMessage {
+            message_id: "ZACKAAAAQX2MD",
+            zone: &zone,
+            resolver: Some("192.0.2.53:5353".parse()?),
+            first_chunk_at: at,
+            last_chunk_at: at + Duration::from_secs(3),
+            raw,
+            scan: Some(&scan),
+            place: Some(&place),
+        };
+        let row = storage.record_message(&message)?;
+        let ellipse = ErrorEllipse { semi_major: 40.0, semi_minor: 20.0, orientation: 15.0 };
+        storage.record_location(row, &Location { lat: 1.5, lng: 2.5, accuracy: 30.0, ellipse: Some(ellipse.clone()) })?;
+        // Messages can be stored out of order, the device was first seen by
+        // whichever started earliest
+        message.message_id = "ZACKAAAARRRRE";
+        message.first_chunk_at = at - Duration::from_secs(100);
+        message.last_chunk_at = at + Duration::from_secs(60);
+        message.scan = None;
+        storage.record_message(&message)?;
+
+        let (boot_counter, resolver, ssid, place): (u8, String, String, String) = storage.connection.query_row(
+            "SELECT boot_counter, resolver, ssid, place FROM messages WHERE id = ?1", [row],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )?;
+        assert_eq!((boot_counter, resolver.as_str(), ssid.as_str(), place.as_str()), (3, "192.0.2.53:5353", "Starbucks WiFi", "depot 3"));
+        let aps: i64 = storage.connection.query_row("SELECT COUNT(*) FROM access_points WHERE message = ?1", [row], |row| row.get(0))?;
+        assert_eq!(aps, 10);
+        let lat: f64 = storage.connection.query_row("SELECT lat FROM locations WHERE message = ?1", [row], |row| row.get(0))?;
+        assert_eq!(lat, 1.5);
+        let (first_seen, last_seen): (i64, i64) = storage.connection.query_row(
+            "SELECT first_seen, last_seen FROM devices WHERE id = 'ZACKAAAA'", [], |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        assert_eq!((first_seen, last_seen), (1_599_999_900, 1_600_000_060));
+
+        // Only the located message has a fix
+        let fixes = storage.fixes("ZACKAAAA", None, None)?;
+        assert_eq!(fixes.len(), 1);
+        assert_eq!((fixes[0].access_points, fixes[0].location.lat), (10, 1.5));
+        assert_eq!(fixes[0].location.ellipse, Some(ellipse));
+        assert_eq!(fixes[0].received_at, at + Duration::from_secs(3));
+        assert!(storage.fixes("ZACKAAAA", Some(at + Duration::from_secs(4)), None)?.is_empty());
+        assert!(storage.fixes("ZACKAAAB", None, None)?.is_empty());
+        assert_eq!(storage.latest_fix("ZACKAAAA")?, Some(fixes[0].clone()));
+
+        let devices = storage.devices()?;
+        assert_eq!((devices.len(), devices[0].messages), (1, 2));
+        let stored = storage.message("ZACKAAAARRRRE")?.unwrap();
+        assert_eq!((stored.raw.as_slice(), stored.location), (&raw[..], None));
+        assert_eq!(storage.message("ZACKAAAAQX2MD")?.unwrap().location.map(|location| location.lat), Some(1.5));
+        assert_eq!(storage.message("ZACKAAAAAAAAA")?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+// Helpers shared by the tests of several modules
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::payload::{AccessPoint, MacAddress, WifiScan};
+
+/// A scan of the APs 00:00:00:00:00:XX, given as (XX, RSSI)
+pub fn scan(aps: &[(u8, i8)]) -> WifiScan {
+    WifiScan {
+        version: 0,
+        access_points: aps.iter()
+            .map(|(last, rssi)| AccessPoint { mac_address: MacAddress([0, 0, 0, 0, 0, *last]), channel: 1, rssi: *rssi })
+            .collect(),
+        ssid: String::new(),
+    }
+}
+
+/// A scan of the APs with these MACs, all heard at -70dBm
+pub fn scan_of_macs(macs: &[&str]) -> WifiScan {
+    WifiScan {
+        version: 0,
+        access_points: macs.iter()
+            .map(|mac| AccessPoint { mac_address: MacAddress::parse(mac).unwrap(), channel: 1, rssi: -70 })
+            .collect(),
+        ssid: String::new(),
+    }
+}
+
+/// A request received by `http_stand_in`
+#[derive(Debug)]
+pub struct StandInRequest {
+    pub request_line: String,
+    // Names lowercased
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+/// Answer one HTTP request per `(status, body)` in turn, returning the base
+/// URL to call along with the requests as they arrive
+pub fn http_stand_in(responses: &[(&'static str, &'static str)]) -> (String, Receiver<StandInRequest>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let responses = responses.to_vec();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for (status, body) in responses {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut headers = HashMap::new();
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                match header.split_once(':') {
+                    Some((name, value)) => headers.insert(name.to_ascii_lowercase(), value.trim().to_string()),
+                    None => break,
+                };
+            }
+            let content_length = headers.get("content-length").map_or(0, |length| length.parse().unwrap());
+            let mut request_body = vec![0; content_length];
+            reader.read_exact(&mut request_body).unwrap();
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status, body.len(), body
+            );
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+            let request = StandInRequest {
+                request_line: request_line.trim().to_string(),
+                headers,
+                body: String::from_utf8(request_body).unwrap(),
+            };
+            // The test may have stopped listening
+            let _ = sender.send(request);
+        }
+    });
+    (base_url, receiver)
+}
+
+/// A file in the temporary directory named for this test run, removed when
+/// dropped along with any journal SQLite left beside it
+#[derive(Debug)]
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    pub fn new(name: &str) -> Self {
+        let path = TempPath(std::env::temp_dir().join(format!("dns_drop_{}_{}", std::process::id(), name)));
+        path.remove();
+        path
+    }
+
+    fn remove(&self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", self.0.display(), suffix));
+        }
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
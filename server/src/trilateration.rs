@@ -0,0 +1,317 @@
+use serde::Deserialize;
+
+use crate::ap_database::{ApDatabase, KnownAp};
+use crate::geolocation::{ErrorEllipse, Geolocator, Location, EARTH_RADIUS_M};
+use crate::payload::WifiScan;
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+// Fewer known APs than this can't pin down a position, use the centroid
+const MIN_APS: usize = 3;
+// Spread of RSSI readings around the model, from fading and body shadowing
+const RSSI_SIGMA_DB: f64 = 4.0;
+// How far off an AP's recorded position may be when the database doesn't say
+const DEFAULT_AP_POSITION_ERROR_M: f64 = 20.0;
+const MAX_ITERATIONS: usize = 50;
+const CONVERGED_M: f64 = 0.01;
+// Scales a one sigma ellipse to hold 95% of fixes (chi-squared, 2 degrees of freedom)
+const ELLIPSE_95: f64 = 2.447_746_830_680_816;
+
+/// Log-distance path loss: `rssi = rssi_at_1m - 10 * exponent * log10(d)`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct PathLossModel {
+    pub rssi_at_1m: f64,
+    pub exponent: f64,
+}
+
+impl PathLossModel {
+    /// Distance in metres at which the model gives `rssi`
+    pub fn distance(&self, rssi: f64) -> f64 {
+        10f64.powf((self.rssi_at_1m - rssi) / (10.0 * self.exponent))
+    }
+
+    // A non-positive exponent gives infinite or inverted distances, which
+    // would quietly spoil every fix
+    fn validate(&self, band: &str) -> Result<()> {
+        if !(self.exponent.is_finite() && self.exponent > 0.0) {
+            return Err(format!("Path loss exponent for {} must be above 0, not {}", band, self.exponent).into());
+        }
+        if !(self.rssi_at_1m.is_finite() && self.rssi_at_1m < 0.0) {
+            return Err(format!("Path loss RSSI at 1m for {} must be below 0dBm, not {}", band, self.rssi_at_1m).into());
+        }
+        Ok(())
+    }
+
+    /// One sigma error of `distance`, which grows with the distance itself
+    fn distance_sigma(&self, distance: f64) -> f64 {
+        distance * std::f64::consts::LN_10 / (10.0 * self.exponent) * RSSI_SIGMA_DB
+    }
+}
+
+/// Path loss per band, see path_loss_sample.json. The defaults suit APs
+/// indoors seen from a short way off.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Calibration {
+    #[serde(rename = "2.4ghz")]
+    pub band_2_4ghz: PathLossModel,
+    #[serde(rename = "5ghz")]
+    pub band_5ghz: PathLossModel,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration {
+            band_2_4ghz: PathLossModel { rssi_at_1m: -40.0, exponent: 3.0 },
+            band_5ghz: PathLossModel { rssi_at_1m: -47.0, exponent: 3.3 },
+        }
+    }
+}
+
+impl Calibration {
+    pub fn load(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read calibration {}: {}", path, e))?;
+        let calibration: Calibration = serde_json::from_str(&contents)?;
+        calibration.validate()?;
+        Ok(calibration)
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.band_2_4ghz.validate("2.4GHz")?;
+        self.band_5ghz.validate("5GHz")
+    }
+
+    /// The model for a channel, 2.4GHz covering channels 1 to 14
+    pub fn model(&self, channel: u8) -> &PathLossModel {
+        match channel {
+            32..=177 => &self.band_5ghz,
+            _ => &self.band_2_4ghz,
+        }
+    }
+}
+
+/// Positions scans by turning each RSSI into a distance and finding the
+/// point which best fits the distances to the known APs
+pub struct Trilaterator {
+    database: ApDatabase,
+    calibration: Calibration,
+}
+
+// A known AP in metres east and north of the origin, with the distance to it
+struct Range {
+    x: f64,
+    y: f64,
+    distance: f64,
+    weight: f64,
+}
+
+impl Trilaterator {
+    pub fn new(database: ApDatabase, calibration: Calibration) -> Self {
+        Trilaterator { database, calibration }
+    }
+
+    /// Weighted least squares fix, None if the APs don't constrain one (fewer
+    /// than three, or all in a line)
+    fn trilaterate(&self, scan: &WifiScan) -> Option<Location> {
+        let known: Vec<(&KnownAp, &PathLossModel, f64)> = scan.access_points.iter()
+            .filter_map(|ap| {
+                let known = self.database.get(&ap.mac_address)?;
+                Some((known, self.calibration.model(ap.channel), ap.rssi as f64))
+            })
+            .collect();
+        if known.len() < MIN_APS {
+            return None;
+        }
+
+        // Work in metres on a plane through the first AP, fine over the few
+        // hundred metres WiFi reaches
+        let (lat0, lng0) = (known[0].0.lat, known[0].0.lng);
+        let ranges: Vec<Range> = known.iter()
+            .map(|(ap, model, rssi)| {
+                let (x, y) = to_plane(lat0, lng0, ap.lat, ap.lng);
+                let distance = model.distance(*rssi);
+                let position_error = ap.accuracy.unwrap_or(DEFAULT_AP_POSITION_ERROR_M);
+                let variance = model.distance_sigma(distance).powi(2) + position_error.powi(2);
+                Range { x, y, distance, weight: 1.0 / variance }
+            })
+            .collect();
+
+        // Gauss-Newton from the weighted centroid
+        let total: f64 = ranges.iter().map(|range| range.weight).sum();
+        let mut x = ranges.iter().map(|range| range.x * range.weight).sum::<f64>() / total;
+        let mut y = ranges.iter().map(|range| range.y * range.weight).sum::<f64>() / total;
+        let mut normal = [0.0; 3];
+        for _ in 0..MAX_ITERATIONS {
+            // Normal equations [a b; b c] * step = -[gx gy]
+            let (mut a, mut b, mut c, mut gx, mut gy) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for range in &ranges {
+                let (dx, dy) = (x - range.x, y - range.y);
+                let r = dx.hypot(dy).max(CONVERGED_M);
+                let (jx, jy) = (dx / r, dy / r);
+                let residual = r - range.distance;
+                a += range.weight * jx * jx;
+                b += range.weight * jx * jy;
+                c += range.weight * jy * jy;
+                gx += range.weight * jx * residual;
+                gy += range.weight * jy * residual;
+            }
+            let det = a * c - b * b;
+            if det.abs() < 1e-12 * (a * c).max(f64::MIN_POSITIVE) {
+                return None;
+            }
+            let step_x = -(c * gx - b * gy) / det;
+            let step_y = -(a * gy - b * gx) / det;
+            x += step_x;
+            y += step_y;
+            normal = [a, b, c];
+            if step_x.hypot(step_y) < CONVERGED_M {
+                break;
+            }
+        }
+        if !x.is_finite() || !y.is_finite() {
+            return None;
+        }
+
+        // Covariance is the inverse of the normal matrix, scaled up when the
+        // distances fit each other worse than the weights expect
+        let chi2: f64 = ranges.iter()
+            .map(|range| range.weight * ((x - range.x).hypot(y - range.y) - range.distance).powi(2))
+            .sum();
+        let scale = (chi2 / (ranges.len() - 2) as f64).max(1.0);
+        let [a, b, c] = normal;
+        let det = a * c - b * b;
+        let ellipse = error_ellipse(c / det * scale, -b / det * scale, a / det * scale);
+
+        let (lat, lng) = from_plane(lat0, lng0, x, y);
+        Some(Location { lat, lng, accuracy: ellipse.semi_major, ellipse: Some(ellipse) })
+    }
+}
+
+impl Geolocator for Trilaterator {
+    fn locate(&self, scan: &WifiScan) -> Result<Location> {
+        match self.trilaterate(scan) {
+            Some(location) => Ok(location),
+            None => self.database.locate(scan),
+        }
+    }
+}
+
+/// The 95% ellipse of the covariance [xx xy; xy yy], x east and y north
+fn error_ellipse(xx: f64, xy: f64, yy: f64) -> ErrorEllipse {
+    let mean = (xx + yy) / 2.0;
+    let spread = (((xx - yy) / 2.0).powi(2) + xy * xy).sqrt();
+    // Angle of the major axis counterclockwise from east, turned into a bearing
+    let angle = 0.5 * (2.0 * xy).atan2(xx - yy);
+    ErrorEllipse {
+        semi_major: (mean + spread).max(0.0).sqrt() * ELLIPSE_95,
+        semi_minor: (mean - spread).max(0.0).sqrt() * ELLIPSE_95,
+        orientation: (90.0 - angle.to_degrees()).rem_euclid(180.0),
+    }
+}
+
+fn to_plane(lat0: f64, lng0: f64, lat: f64, lng: f64) -> (f64, f64) {
+    let x = (lng - lng0).to_radians() * EARTH_RADIUS_M * lat0.to_radians().cos();
+    let y = (lat - lat0).to_radians() * EARTH_RADIUS_M;
+    (x, y)
+}
+
+fn from_plane(lat0: f64, lng0: f64, x: f64, y: f64) -> (f64, f64) {
+    let lat = lat0 + (y / EARTH_RADIUS_M).to_degrees();
+    let lng = lng0 + (x / (EARTH_RADIUS_M * lat0.to_radians().cos())).to_degrees();
+    (lat, lng)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geolocation::distance_m;
+    use crate::payload::{AccessPoint, MacAddress};
+
+    const LAT0: f64 = 51.5;
+    const LNG0: f64 = -0.12;
+
+    /// A database holding APs at the given spots in metres east and north,
+    /// and a scan of them from `(x, y)` with RSSIs following the model
+    fn setup(aps: &[(f64, f64)], x: f64, y: f64) -> (Trilaterator, WifiScan) {
+        let calibration = Calibration::default();
+        let model = calibration.band_2_4ghz;
+        let mut csv = "bssid,lat,lon,accuracy\n".to_string();
+        let mut access_points = Vec::new();
+        for (i, (ap_x, ap_y)) in aps.iter().enumerate() {
+            let (lat, lng) = from_plane(LAT0, LNG0, *ap_x, *ap_y);
+            csv.push_str(&format!("00:00:00:00:00:{:02x},{},{},1\n", i + 1, lat, lng));
+            let distance = (x - ap_x).hypot(y - ap_y);
+            let rssi = model.rssi_at_1m - 10.0 * model.exponent * distance.log10();
+            access_points.push(AccessPoint {
+                mac_address: MacAddress([0, 0, 0, 0, 0, i as u8 + 1]),
+                channel: 6,
+                rssi: rssi.round() as i8,
+            });
+        }
+        let database = ApDatabase::parse(csv.as_bytes()).unwrap();
+        let scan = WifiScan { version: 0, access_points, ssid: String::new() };
+        (Trilaterator::new(database, calibration), scan)
+    }
+
+    #[test]
+    fn test_path_loss() {
+        let calibration = Calibration::default();
+        assert!((calibration.model(1).distance(-40.0) - 1.0).abs() < 1e-9);
+        assert!((calibration.model(11).distance(-70.0) - 10.0).abs() < 1e-9);
+        assert_eq!(calibration.model(36), &calibration.band_5ghz);
+        let calibration: Calibration = serde_json::from_str(
+            r#"{"2.4ghz": {"rssi_at_1m": -45, "exponent": 2.5}, "5ghz": {"rssi_at_1m": -50, "exponent": 3}}"#,
+        ).unwrap();
+        assert_eq!(calibration.band_2_4ghz, PathLossModel { rssi_at_1m: -45.0, exponent: 2.5 });
+    }
+
+    #[test]
+    fn test_calibration() -> Result<()> {
+        assert_eq!(Calibration::load("path_loss_sample.json")?, Calibration::default());
+        let mut calibration = Calibration::default();
+        calibration.band_5ghz.exponent = 0.0;
+        assert_eq!(calibration.validate().unwrap_err().to_string(), "Path loss exponent for 5GHz must be above 0, not 0");
+        calibration.band_5ghz.exponent = f64::NAN;
+        assert!(calibration.validate().is_err());
+        let mut calibration = Calibration::default();
+        calibration.band_2_4ghz.rssi_at_1m = f64::INFINITY;
+        assert!(calibration.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_trilaterate() -> Result<()> {
+        let (trilaterator, scan) = setup(&[(0.0, 0.0), (40.0, 0.0), (0.0, 40.0), (40.0, 40.0)], 15.0, 25.0);
+        let location = trilaterator.locate(&scan)?;
+        let (lat, lng) = from_plane(LAT0, LNG0, 15.0, 25.0);
+        assert!(distance_m(location.lat, location.lng, lat, lng) < 2.0);
+        let ellipse = location.ellipse.unwrap();
+        assert!(ellipse.semi_major >= ellipse.semi_minor);
+        assert_eq!(location.accuracy, ellipse.semi_major);
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_ellipse() {
+        // Known much better east-west than north-south
+        let ellipse = error_ellipse(1.0, 0.0, 16.0);
+        assert!((ellipse.semi_major - 4.0 * ELLIPSE_95).abs() < 1e-9);
+        assert!((ellipse.semi_minor - ELLIPSE_95).abs() < 1e-9);
+        assert!(ellipse.orientation.abs() < 1e-9);
+        assert!((error_ellipse(16.0, 0.0, 1.0).orientation - 90.0).abs() < 1e-9);
+        assert!((error_ellipse(2.0, 1.0, 2.0).orientation - 45.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_fallback() -> Result<()> {
+        // Two APs can't fix a position, nor can three in a row
+        for aps in &[vec![(0.0, 0.0), (40.0, 0.0)], vec![(0.0, 0.0), (20.0, 0.0), (40.0, 0.0)]] {
+            let (trilaterator, scan) = setup(aps, 10.0, 0.0);
+            let location = trilaterator.locate(&scan)?;
+            assert_eq!(location, trilaterator.database.locate(&scan)?);
+            assert_eq!(location.ellipse, None);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,381 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, SystemTime};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::events::MessageEvent;
+
+type Error = Box<dyn std::error::Error>;
+type Result<T> = std::result::Result<T, Error>;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+// With the defaults, about three and a half hours of trying
+const DEFAULT_MAX_ATTEMPTS: u32 = 12;
+
+/// A URL to POST events to, with the secret to sign them with if any
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Webhook {
+    pub url: String,
+    pub secret: Option<String>,
+}
+
+// One event on its way to one webhook. Secrets aren't kept on disk, the
+// body is signed when sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Delivery {
+    id: u64,
+    url: String,
+    event: String,
+    body: String,
+    attempts: u32,
+    // Unix time in milliseconds
+    next_attempt: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QueueFile {
+    deliveries: Vec<Delivery>,
+    // Kept so IDs aren't reused once the queue empties, receivers
+    // deduplicate on them
+    #[serde(default)]
+    next_id: u64,
+}
+
+enum Outcome {
+    Delivered,
+    // Worth trying again later, the receiver is down or struggling
+    Failed(String),
+    // The receiver won't ever take it
+    Rejected(String),
+}
+
+/// Events waiting to be delivered to each webhook, retried with exponential
+/// backoff until they're taken
+pub struct WebhookQueue {
+    webhooks: Vec<Webhook>,
+    deliveries: Vec<Delivery>,
+    next_id: u64,
+    path: Option<PathBuf>,
+    initial_backoff: Duration,
+    max_attempts: u32,
+    agent: ureq::Agent,
+}
+
+impl WebhookQueue {
+    pub fn new(webhooks: Vec<Webhook>) -> Self {
+        WebhookQueue {
+            webhooks,
+            deliveries: Vec::new(),
+            next_id: 1,
+            path: None,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            agent: ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build(),
+        }
+    }
+
+    /// Wait after the first failed attempt, doubling with each failure up to an hour
+    pub fn with_backoff(mut self, initial: Duration) -> Self {
+        self.initial_backoff = initial;
+        self
+    }
+
+    /// Attempts at a delivery before it's given up on
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Keep undelivered events in `path`, picking up those left from before.
+    /// Events for webhooks no longer configured are dropped.
+    pub fn persist_to(mut self, path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                let queue_file: QueueFile = serde_json::from_str(&contents)
+                    .map_err(|e| format!("Invalid webhook queue {}: {}", path.display(), e))?;
+                self.next_id = self.next_id.max(queue_file.next_id);
+                for delivery in queue_file.deliveries {
+                    if self.webhooks.iter().any(|webhook| webhook.url == delivery.url) {
+                        self.next_id = self.next_id.max(delivery.id + 1);
+                        self.deliveries.push(delivery);
+                    } else {
+                        eprintln!("Dropping {} delivery {} to {}, no longer a webhook", delivery.event, delivery.id, delivery.url);
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Unable to read webhook queue {}: {}", path.display(), e).into()),
+        }
+        self.path = Some(path.to_path_buf());
+        Ok(self)
+    }
+
+    /// Deliveries waiting for their next attempt
+    pub fn len(&self) -> usize {
+        self.deliveries.len()
+    }
+
+    /// Queue `event` for every webhook, due straight away
+    pub fn enqueue(&mut self, event: &MessageEvent) -> Result<()> {
+        let body = serde_json::to_string(event)?;
+        let now = unix_millis(SystemTime::now());
+        for webhook in &self.webhooks {
+            self.deliveries.push(Delivery {
+                id: self.next_id,
+                url: webhook.url.clone(),
+                event: event.event.to_string(),
+                body: body.clone(),
+                attempts: 0,
+                next_attempt: now,
+            });
+            self.next_id += 1;
+        }
+        self.save()
+    }
+
+    /// Queue every event waiting in `events`, without blocking
+    fn enqueue_waiting(&mut self, events: &Receiver<MessageEvent>) {
+        while let Ok(event) = events.try_recv() {
+            if let Err(e) = self.enqueue(&event) {
+                eprintln!("Unable to queue {} of {}: {}", event.event, event.message_id, e);
+            }
+        }
+    }
+
+    /// Attempt every delivery which is due, returning how long until the next
+    /// one is, if any are left. Events arriving on `events` meanwhile are
+    /// queued before each attempt, which may take a while, so they're on disk.
+    pub fn deliver_due(&mut self, events: &Receiver<MessageEvent>) -> Option<Duration> {
+        let now = unix_millis(SystemTime::now());
+        let due: Vec<u64> = self.deliveries.iter()
+            .filter(|delivery| delivery.next_attempt <= now)
+            .map(|delivery| delivery.id)
+            .collect();
+        for id in &due {
+            self.enqueue_waiting(events);
+            let index = self.deliveries.iter().position(|delivery| delivery.id == *id).unwrap();
+            let outcome = self.attempt(&self.deliveries[index]);
+            let delivery = &mut self.deliveries[index];
+            delivery.attempts += 1;
+            match outcome {
+                Outcome::Delivered => {
+                    println!("Delivered {} {} to {}", delivery.event, delivery.id, delivery.url);
+                    self.deliveries.remove(index);
+                }
+                Outcome::Rejected(e) => {
+                    eprintln!("Dropping {} delivery {} to {}: {}", delivery.event, delivery.id, delivery.url, e);
+                    self.deliveries.remove(index);
+                }
+                Outcome::Failed(e) if delivery.attempts >= self.max_attempts => {
+                    eprintln!("Giving up on {} delivery {} to {} after {} attempts: {}", delivery.event, delivery.id, delivery.url, delivery.attempts, e);
+                    self.deliveries.remove(index);
+                }
+                Outcome::Failed(e) => {
+                    let backoff = backoff(self.initial_backoff, delivery.attempts);
+                    eprintln!("Unable to deliver {} {} to {}, retrying in {}s: {}", delivery.event, delivery.id, delivery.url, backoff.as_secs(), e);
+                    delivery.next_attempt = unix_millis(SystemTime::now()) + backoff.as_millis() as u64;
+                }
+            }
+        }
+        if !due.is_empty() {
+            if let Err(e) = self.save() {
+                eprintln!("Unable to save webhook queue: {}", e);
+            }
+        }
+
+        let now = unix_millis(SystemTime::now());
+        self.deliveries.iter()
+            .map(|delivery| Duration::from_millis(delivery.next_attempt.saturating_sub(now)))
+            .min()
+    }
+
+    fn attempt(&self, delivery: &Delivery) -> Outcome {
+        let mut request = self.agent.post(&delivery.url)
+            .set("Content-Type", "application/json")
+            .set("X-Dns-Drop-Event", &delivery.event)
+            .set("X-Dns-Drop-Delivery", &delivery.id.to_string());
+        let secret = self.webhooks.iter()
+            .find(|webhook| webhook.url == delivery.url)
+            .and_then(|webhook| webhook.secret.as_ref());
+        if let Some(secret) = secret {
+            request = request.set("X-Dns-Drop-Signature", &format!("sha256={}", sign(secret, &delivery.body)));
+        }
+        match request.send_string(&delivery.body) {
+            Ok(_) => Outcome::Delivered,
+            // Timeouts and rate limits pass, other client errors won't
+            Err(ureq::Error::Status(status, _)) if status >= 500 || status == 408 || status == 429 => {
+                Outcome::Failed(format!("HTTP {}", status))
+            }
+            Err(ureq::Error::Status(status, _)) => Outcome::Rejected(format!("HTTP {}", status)),
+            Err(e) => Outcome::Failed(e.to_string()),
+        }
+    }
+
+    /// Write the queue out if it's persisted, replacing the file in one go
+    fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let queue_file = QueueFile { deliveries: self.deliveries.clone(), next_id: self.next_id };
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_string(&queue_file)?)
+            .map_err(|e| format!("Unable to write to {}: {}", tmp_path.display(), e))?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Hex HMAC-SHA256 of `body`, so receivers can check an event came from us
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body.as_bytes());
+    mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn backoff(initial: Duration, attempts: u32) -> Duration {
+    initial.saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1))).min(MAX_BACKOFF)
+}
+
+fn unix_millis(at: SystemTime) -> u64 {
+    at.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Deliver events as they come in, retrying failed deliveries in between
+pub fn deliver_webhooks(mut queue: WebhookQueue, events: Receiver<MessageEvent>) {
+    loop {
+        let event = match queue.deliver_due(&events) {
+            Some(wait) => match events.recv_timeout(wait) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return,
+            },
+            None => match events.recv() {
+                Ok(event) => event,
+                Err(_) => return,
+            },
+        };
+        if let Err(e) = queue.enqueue(&event) {
+            eprintln!("Unable to queue {} of {}: {}", event.event, event.message_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+    use crate::events::MESSAGE_LOCATED;
+    use crate::geolocation::Location;
+    use crate::test_util::{http_stand_in, StandInRequest, TempPath};
+    use serde_json::Value;
+
+    /// A stand-in answering with each status in turn, returning the URL to call
+    fn stand_in(statuses: &[&'static str]) -> (String, Receiver<StandInRequest>) {
+        let responses: Vec<_> = statuses.iter().map(|status| (*status, "")).collect();
+        let (base_url, requests) = http_stand_in(&responses);
+        (format!("{}/hook", base_url), requests)
+    }
+
+    fn event() -> MessageEvent {
+        MessageEvent {
+            event: MESSAGE_LOCATED,
+            device_id: "ZACKAAAA".to_string(),
+            message_id: "ZACKAAAAQX2MD".to_string(),
+            zone: "i.mdp.im".to_string(),
+            first_chunk_at: "2020-09-13T12:26:40Z".to_string(),
+            last_chunk_at: "2020-09-13T12:26:43Z".to_string(),
+            payload: Value::String("hello".to_string()),
+            place: None,
+            location: Some(Location { lat: 51.5, lng: -0.12, accuracy: 20.0, ellipse: None }),
+        }
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2
+        assert_eq!(sign("Jefe", "what do ya want for nothing?"), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        assert_eq!(backoff(Duration::from_secs(10), 1), Duration::from_secs(10));
+        assert_eq!(backoff(Duration::from_secs(10), 3), Duration::from_secs(40));
+        assert_eq!(backoff(Duration::from_secs(10), 40), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_retry_from_disk() -> Result<()> {
+        let path = TempPath::new("webhooks.json");
+        let (url, requests) = stand_in(&["503 Service Unavailable", "200 OK"]);
+        let webhooks = vec![Webhook { url, secret: Some("s3cret".to_string()) }];
+        let mut queue = WebhookQueue::new(webhooks.clone())
+            .with_backoff(Duration::from_millis(20))
+            .persist_to(&path)?;
+        queue.enqueue(&event())?;
+        let (_, incoming) = mpsc::channel();
+        let wait = queue.deliver_due(&incoming).unwrap();
+        assert!(wait <= Duration::from_millis(20));
+        let StandInRequest { headers, body, .. } = requests.recv()?;
+        assert_eq!(headers["x-dns-drop-event"], MESSAGE_LOCATED);
+        assert_eq!(headers["x-dns-drop-signature"], format!("sha256={}", sign("s3cret", &body)));
+        let sent: Value = serde_json::from_str(&body)?;
+        assert_eq!((sent["deviceId"].as_str(), sent["location"]["lat"].as_f64()), (Some("ZACKAAAA"), Some(51.5)));
+
+        // Picked up again after a restart, and delivered once due
+        let mut queue = WebhookQueue::new(webhooks).persist_to(&path)?;
+        assert_eq!(queue.len(), 1);
+        thread::sleep(wait);
+        assert_eq!(queue.deliver_due(&incoming), None);
+        let retried = requests.recv()?;
+        assert_eq!((retried.headers["x-dns-drop-delivery"].as_str(), retried.body), ("1", body));
+        assert_eq!(WebhookQueue::new(vec![]).persist_to(&path)?.len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_give_up() -> Result<()> {
+        let (rejecting, _requests) = stand_in(&["410 Gone"]);
+        let (failing, _requests) = stand_in(&["500 Internal Server Error", "502 Bad Gateway"]);
+        let mut queue = WebhookQueue::new(vec![Webhook { url: rejecting, secret: None }, Webhook { url: failing, secret: None }])
+            .with_backoff(Duration::ZERO)
+            .with_max_attempts(2);
+        queue.enqueue(&event())?;
+        // Rejected outright, the other left to retry
+        let (_, incoming) = mpsc::channel();
+        assert_eq!(queue.deliver_due(&incoming), Some(Duration::ZERO));
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.deliver_due(&incoming), None);
+        assert_eq!(queue.len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_queued_while_delivering() -> Result<()> {
+        let path = TempPath::new("webhooks_queued.json");
+        // Nothing listens there once the listener is dropped, attempts fail straight away
+        let url = format!("http://{}/hook", TcpListener::bind("127.0.0.1:0")?.local_addr()?);
+        let webhooks = vec![Webhook { url, secret: None }];
+        let mut queue = WebhookQueue::new(webhooks.clone()).with_max_attempts(1).persist_to(&path)?;
+        queue.enqueue(&event())?;
+
+        // Events sent meanwhile are on disk before the attempt
+        let (sender, incoming) = mpsc::channel();
+        sender.send(event())?;
+        assert_eq!(queue.deliver_due(&incoming), Some(Duration::ZERO));
+        assert_eq!(WebhookQueue::new(webhooks.clone()).persist_to(&path)?.len(), 1);
+        assert_eq!(queue.deliver_due(&incoming), None);
+
+        // Delivery IDs carry on after the queue empties and the server restarts
+        let mut queue = WebhookQueue::new(webhooks).persist_to(&path)?;
+        assert_eq!(queue.len(), 0);
+        queue.enqueue(&event())?;
+        assert_eq!(queue.deliveries[0].id, 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::time::SystemTime;
+
+use serde::Deserialize;
+
+use crate::dns::{DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode};
+
+const SOA_TTL: u32 = 3600;
+const NS_TTL: u32 = 86400;
+// Used as the negative caching TTL (RFC 2308). Tunnel names are never reused,
+// so there's little point in resolvers holding on to NXDOMAIN/NODATA for long
+const SOA_MINIMUM: u32 = 60;
+
+/// A domain name held as lowercase labels, so names are compared label by
+/// label and case-insensitively rather than as strings
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DomainName {
+    labels: Vec<String>,
+}
+
+impl DomainName {
+    pub fn new(name: &str) -> Self {
+        DomainName {
+            labels: name
+                .split('.')
+                .filter(|label| !label.is_empty())
+                .map(|label| label.to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    /// Whether we are `other` or any name below it
+    pub fn is_subdomain_of(&self, other: &DomainName) -> bool {
+        self.labels.ends_with(&other.labels)
+    }
+
+    /// The labels in front of `suffix`, or None if we aren't below it
+    pub fn strip_suffix(&self, suffix: &DomainName) -> Option<&[String]> {
+        self.labels.strip_suffix(suffix.labels.as_slice())
+    }
+}
+
+impl From<&str> for DomainName {
+    fn from(name: &str) -> Self {
+        DomainName::new(name)
+    }
+}
+
+impl fmt::Display for DomainName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.labels.join("."))
+    }
+}
+
+/// How to answer devices sending chunks through a zone
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponsePolicy {
+    // Encode the time and completion state in an A record (10.x.x.x / 11.x.x.x)
+    Ack,
+    // Answer NODATA, for fleets which never look at the reply
+    NoData,
+}
+
+/// A zone we are authoritative for, i.e. a root domain devices tunnel through,
+/// along with what to do with the messages arriving on it
+#[derive(Debug, Clone)]
+pub struct Zone {
+    pub apex: DomainName,
+    pub nameservers: Vec<DomainName>,
+    // Our public address, handed out as glue for nameservers inside the zone
+    pub address: Option<Ipv4Addr>,
+    pub hostmaster: DomainName,
+    pub serial: u32,
+    pub output_dir: String,
+    // Device IDs allowed to send through this zone, None allows everyone
+    pub allowed_devices: Option<HashSet<String>>,
+    pub response_policy: ResponsePolicy,
+}
+
+impl Zone {
+    pub fn new(apex: &str) -> Self {
+        let apex = DomainName::new(apex);
+        let serial = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Can't get Unix time")
+            .as_secs() as u32;
+        Zone {
+            nameservers: vec![DomainName::new(&format!("ns1.{}", apex))],
+            address: None,
+            hostmaster: DomainName::new(&format!("hostmaster.{}", apex)),
+            serial,
+            apex,
+            output_dir: String::new(),
+            allowed_devices: None,
+            response_policy: ResponsePolicy::Ack,
+        }
+    }
+
+    pub fn allows_device(&self, device_id: &str) -> bool {
+        match &self.allowed_devices {
+            Some(allowed) => allowed.contains(device_id),
+            None => true,
+        }
+    }
+
+    /// Whether `name` is the apex or any name below it
+    pub fn contains(&self, name: &DomainName) -> bool {
+        name.is_subdomain_of(&self.apex)
+    }
+
+    pub fn is_apex(&self, name: &DomainName) -> bool {
+        *name == self.apex
+    }
+
+    fn is_nameserver(&self, name: &DomainName) -> bool {
+        self.nameservers.contains(name)
+    }
+
+    pub fn soa(&self) -> DnsRecord {
+        DnsRecord::SOA {
+            domain: self.apex.to_string(),
+            m_name: self.nameservers[0].to_string(),
+            r_name: self.hostmaster.to_string(),
+            serial: self.serial,
+            refresh: 3600,
+            retry: 600,
+            expire: 604800,
+            minimum: SOA_MINIMUM,
+            ttl: SOA_TTL,
+        }
+    }
+
+    pub fn ns_records(&self) -> Vec<DnsRecord> {
+        self.nameservers
+            .iter()
+            .map(|ns| DnsRecord::NS {
+                domain: self.apex.to_string(),
+                host: ns.to_string(),
+                ttl: NS_TTL,
+            })
+            .collect()
+    }
+
+    /// A records for nameservers which live inside the zone, resolvers can't
+    /// find them otherwise
+    pub fn glue_records(&self) -> Vec<DnsRecord> {
+        match self.address {
+            Some(addr) => self
+                .nameservers
+                .iter()
+                .filter(|ns| self.contains(ns))
+                .map(|ns| DnsRecord::A {
+                    domain: ns.to_string(),
+                    addr,
+                    ttl: NS_TTL,
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Answer questions about the zone itself (the apex and its nameservers)
+    /// rather than tunnelled data. Returns false if the question is for some
+    /// other name in the zone, which the caller then handles.
+    pub fn answer_static(&self, question: &DnsQuestion, packet: &mut DnsPacket) -> bool {
+        let name = DomainName::new(&question.name);
+        if self.is_apex(&name) {
+            match question.qtype {
+                QueryType::SOA => {
+                    packet.answers.push(self.soa());
+                    packet.authorities.extend(self.ns_records());
+                    packet.resources.extend(self.glue_records());
+                }
+                QueryType::NS => {
+                    packet.answers.extend(self.ns_records());
+                    packet.resources.extend(self.glue_records());
+                }
+                _ => self.answer_nodata(packet),
+            }
+            return true;
+        }
+
+        if self.is_nameserver(&name) {
+            match (question.qtype, self.address) {
+                (QueryType::A, Some(addr)) => packet.answers.push(DnsRecord::A {
+                    domain: question.name.clone(),
+                    addr,
+                    ttl: NS_TTL,
+                }),
+                _ => self.answer_nodata(packet),
+            }
+            return true;
+        }
+
+        false
+    }
+
+    /// The name exists but has no records of the requested type (RFC 2308 2.2)
+    pub fn answer_nodata(&self, packet: &mut DnsPacket) {
+        packet.header.rescode = ResultCode::NOERROR;
+        packet.authorities.push(self.soa());
+    }
+}
+
+/// The most specific of `zones` holding `name`, zones may be nested
+pub fn find_zone<'a>(zones: &'a [Zone], name: &DomainName) -> Option<&'a Zone> {
+    zones
+        .iter()
+        .filter(|zone| zone.contains(name))
+        .max_by_key(|zone| zone.apex.labels().len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone() -> Zone {
+        let mut zone = Zone::new("i.mdp.im");
+        zone.address = Some(Ipv4Addr::new(192, 0, 2, 1));
+        zone
+    }
+
+    #[test]
+    fn test_contains() {
+        let zone = zone();
+        assert!(zone.contains(&"i.mdp.im".into()));
+        assert!(zone.contains(&"AAAA.I.MDP.IM.".into()));
+        assert!(!zone.contains(&"mdp.im".into()));
+        assert!(!zone.contains(&"xi.mdp.im".into()));
+        assert!(!zone.contains(&"ixmdpxim".into()));
+        assert!(!zone.contains(&"aaaa.ixmdp.im".into()));
+    }
+
+    #[test]
+    fn test_strip_suffix() {
+        let name = DomainName::new("AADD.PMRG.i.Mdp.im.");
+        let apex = DomainName::new("i.mdp.im");
+        assert_eq!(name.strip_suffix(&apex), Some(&["aadd".to_string(), "pmrg".to_string()][..]));
+        assert_eq!(name.strip_suffix(&"x.mdp.im".into()), None);
+        assert_eq!(name.to_string(), "aadd.pmrg.i.mdp.im");
+    }
+
+    #[test]
+    fn test_find_zone() {
+        let zones = vec![Zone::new("mdp.im"), Zone::new("i.mdp.im"), Zone::new("foo.co")];
+        let found = |name: &str| find_zone(&zones, &name.into()).map(|zone| zone.apex.to_string());
+        assert_eq!(found("aa.i.mdp.im"), Some("i.mdp.im".to_string()));
+        assert_eq!(found("aa.x.mdp.im"), Some("mdp.im".to_string()));
+        assert_eq!(found("AA.FOO.CO"), Some("foo.co".to_string()));
+        assert_eq!(found("afoo.co"), None);
+    }
+
+    #[test]
+    fn test_apex_answers() {
+        let zone = zone();
+
+        let mut packet = DnsPacket::new();
+        assert!(zone.answer_static(&DnsQuestion::new("i.mdp.im".to_string(), QueryType::SOA), &mut packet));
+        assert_eq!(packet.answers, vec![zone.soa()]);
+
+        let mut packet = DnsPacket::new();
+        assert!(zone.answer_static(&DnsQuestion::new("I.mdp.im".to_string(), QueryType::NS), &mut packet));
+        assert_eq!(packet.answers, zone.ns_records());
+        assert_eq!(
+            packet.resources,
+            vec![DnsRecord::A {
+                domain: "ns1.i.mdp.im".to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, 1),
+                ttl: NS_TTL
+            }]
+        );
+
+        let mut packet = DnsPacket::new();
+        assert!(zone.answer_static(&DnsQuestion::new("i.mdp.im".to_string(), QueryType::TXT), &mut packet));
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.authorities, vec![zone.soa()]);
+
+        let mut packet = DnsPacket::new();
+        assert!(!zone.answer_static(&DnsQuestion::new("aa.i.mdp.im".to_string(), QueryType::A), &mut packet));
+    }
+
+    #[test]
+    fn test_out_of_zone_nameserver_has_no_glue() {
+        let mut zone = zone();
+        zone.nameservers = vec!["ns.example.com".into()];
+        assert!(zone.glue_records().is_empty());
+    }
+}